@@ -0,0 +1,210 @@
+use crate::lua_task;
+use crate::task::{ShellCommand, Task};
+use crate::toml_task;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The tasks and global settings found in a configuration file.
+pub struct Config {
+    pub tasks: Vec<Box<dyn Task>>,
+    pub max_parallel: Option<usize>,
+    // Named lists of tags, selected with '--profile'.
+    pub profiles: HashMap<String, Vec<String>>,
+    // Other configuration files to load, listed in 'configs'.
+    pub configs: Vec<PathBuf>,
+    // The configuration file and all files it loaded, e.g. through 'require'.
+    pub files: Vec<PathBuf>,
+}
+
+/// What 'check' found in a configuration file.
+pub struct CheckedFile {
+    pub problems: Vec<ConfigError>,
+    // Other configuration files to check, listed in 'configs'.
+    pub configs: Vec<PathBuf>,
+}
+
+/// Which tasks to use, from the '--profile', '--tag' and '--exclude-tag' flags.
+#[derive(Default)]
+pub struct TagFilter {
+    pub profile: Option<String>,
+    pub tags: Vec<String>,
+    pub exclude_tags: Vec<String>,
+}
+
+impl Config {
+    /// Drops the tasks that 'filter' does not select: tasks without any of the tags of the
+    /// profile and '--tag' (unless there are none) and tasks with any of the excluded tags. The
+    /// profile is looked up in 'profiles', which holds the profiles of all loaded configs.
+    pub fn select_tasks(
+        &mut self,
+        filter: &TagFilter,
+        profiles: &HashMap<String, Vec<String>>,
+    ) -> Result<(), ConfigError> {
+        let mut tags = filter.tags.clone();
+        if let Some(profile) = &filter.profile {
+            match profiles.get(profile) {
+                Some(profile_tags) => tags.extend(profile_tags.iter().cloned()),
+                None => {
+                    return Err(ConfigError::new(
+                        &self.files[0],
+                        format!("Unknown profile '{}'.", profile),
+                    ))
+                }
+            }
+        }
+        self.tasks.retain(|task| {
+            let task_tags = task.tags();
+            let included = tags.is_empty() || task_tags.iter().any(|tag| tags.contains(tag));
+            let excluded = task_tags
+                .iter()
+                .any(|tag| filter.exclude_tags.contains(tag));
+            included && !excluded
+        });
+        Ok(())
+    }
+}
+
+/// A problem with a configuration file, found while loading it or while running one of its
+/// callbacks.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: PathBuf,
+    pub line: Option<u32>,
+    // Describes the task the error is about, e.g. "task #2 (codegen)".
+    pub task: Option<String>,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(file: &Path, message: impl Into<String>) -> Self {
+        ConfigError {
+            file: file.to_path_buf(),
+            line: None,
+            task: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn with_task(mut self, task: impl Into<String>) -> Self {
+        self.task = Some(task.into());
+        self
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.file.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        if let Some(task) = &self.task {
+            write!(f, ": {}", task)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl error::Error for ConfigError {}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "toml")
+}
+
+/// Loads the tasks from 'path', which is a TOML file if it ends in '.toml' and a Lua file
+/// otherwise.
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    if is_toml(path) {
+        toml_task::run_file(path)
+    } else {
+        lua_task::run_file(path)
+    }
+}
+
+/// Like 'load', but returns all problems with the file instead of the tasks.
+pub fn check(path: &Path) -> CheckedFile {
+    if is_toml(path) {
+        toml_task::check_file(path)
+    } else {
+        lua_task::check_file(path)
+    }
+}
+
+/// Returns true if 'program' can be run from 'work_directory', either because it is a path to an
+/// executable or because it is found on the PATH.
+fn program_exists(program: &str, work_directory: Option<&Path>) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    let is_executable = |path: &Path| {
+        path.metadata()
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        let base = work_directory.unwrap_or_else(|| Path::new("."));
+        return is_executable(&base.join(program));
+    }
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|dir| is_executable(&dir.join(program)))
+    })
+}
+
+/// Resolves the files listed in 'configs' against 'config_dir'. Files that do not exist are kept,
+/// loading them reports the problem.
+pub fn included_configs(config_dir: &Path, files: &[String]) -> Vec<PathBuf> {
+    files
+        .iter()
+        .map(|file| {
+            let file = config_dir.join(file);
+            file.canonicalize().unwrap_or(file)
+        })
+        .collect()
+}
+
+/// Resolves the 'watch' directories of a task against 'config_dir'. They must exist, so that they
+/// can be watched and made canonical like the paths of changes.
+pub fn watch_roots(config_dir: &Path, dirs: &[String]) -> Result<Vec<PathBuf>, String> {
+    dirs.iter()
+        .map(|dir| {
+            config_dir
+                .join(dir)
+                .canonicalize()
+                .ok()
+                .filter(|dir| dir.is_dir())
+                .ok_or_else(|| format!("Watch directory '{}' does not exist.", dir))
+        })
+        .collect()
+}
+
+/// Finds programs that are not on the PATH and work directories that do not exist.
+pub fn check_commands(commands: &[ShellCommand]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for command in commands {
+        if let Some(work_directory) = &command.work_directory {
+            if !work_directory.is_dir() {
+                problems.push(format!(
+                    "Work directory '{}' of '{}' does not exist.",
+                    work_directory.display(),
+                    command.name
+                ));
+                continue;
+            }
+        }
+        match command.command.split_whitespace().next() {
+            None => problems.push(format!("Command '{}' is empty.", command.name)),
+            Some(program) if !program_exists(program, command.work_directory.as_deref()) => {
+                let reason = if program.contains('/') {
+                    "is not an executable file"
+                } else {
+                    "is not on the PATH"
+                };
+                problems.push(format!(
+                    "Program '{}' of '{}' {}.",
+                    program, command.name, reason
+                ))
+            }
+            Some(_) => (),
+        }
+    }
+    problems
+}
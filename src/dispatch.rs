@@ -1,91 +1,502 @@
 use notify;
 use time;
 
-use crate::task::{RunningTask, Task};
-use std::borrow::Borrow;
+use crate::config::ConfigError;
+use crate::ignore_filter::IgnoreFilter;
+use crate::task::{
+    self, EventKind, FileEvent, OnChange, ProgressReporter, RunContext, RunningTask, Task,
+};
 use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 
-struct Item<'a> {
+struct Item {
     last_run_requested: Option<time::PreciseTime>,
-    task: &'a dyn Task,
+    // Files that changed since the last run was started.
+    changed_files: Vec<PathBuf>,
     running_task: Option<Box<dyn RunningTask>>,
+    waiting_for_slot: bool,
 }
 
-pub struct ShellGrunt2<'a> {
-    tasks: &'a [Box<dyn Task>],
-    events_rx: mpsc::Receiver<notify::DebouncedEvent>,
+impl Item {
+    fn is_running(&mut self) -> bool {
+        self.running_task.as_mut().is_some_and(|r| !r.done())
+    }
+}
+
+fn display_name(task: &dyn Task, task_idx: usize) -> String {
+    task.name().unwrap_or_else(|| format!("Task #{}", task_idx))
+}
+
+/// Computes for each task the indices of all tasks it transitively depends on. Returns an error
+/// message if a dependency does not exist or the dependencies contain a cycle.
+pub fn dependencies(tasks: &[Box<dyn Task>]) -> Result<Vec<HashSet<usize>>, String> {
+    let tasks = tasks.iter().map(|task| task.as_ref()).collect::<Vec<_>>();
+    resolve_dependencies(&tasks, false)
+}
+
+/// Like 'dependencies', but with 'ignore_unknown' dependencies that do not exist are skipped.
+fn resolve_dependencies(
+    tasks: &[&dyn Task],
+    ignore_unknown: bool,
+) -> Result<Vec<HashSet<usize>>, String> {
+    let mut index_by_name = HashMap::new();
+    for (task_idx, task) in tasks.iter().enumerate() {
+        if let Some(name) = task.name() {
+            if index_by_name.insert(name.clone(), task_idx).is_some() {
+                return Err(format!("Task name '{}' is used more than once.", name));
+            }
+        }
+    }
+
+    let mut direct = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        let mut deps = Vec::new();
+        for dep in task.depends_on() {
+            match index_by_name.get(&dep) {
+                Some(dep_idx) => deps.push(*dep_idx),
+                None if ignore_unknown => (),
+                None => {
+                    return Err(format!(
+                        "Task '{}' depends on unknown task '{}'.",
+                        task.name().unwrap_or_default(),
+                        dep
+                    ))
+                }
+            }
+        }
+        direct.push(deps);
+    }
+
+    // Depth first search that keeps the current path around to report cycles.
+    fn visit(
+        task_idx: usize,
+        direct: &[Vec<usize>],
+        path: &mut Vec<usize>,
+        result: &mut Vec<Option<HashSet<usize>>>,
+    ) -> Result<(), Vec<usize>> {
+        if result[task_idx].is_some() {
+            return Ok(());
+        }
+        if let Some(pos) = path.iter().position(|idx| *idx == task_idx) {
+            let mut cycle = path[pos..].to_vec();
+            cycle.push(task_idx);
+            return Err(cycle);
+        }
+        path.push(task_idx);
+        let mut all = HashSet::new();
+        for dep_idx in &direct[task_idx] {
+            visit(*dep_idx, direct, path, result)?;
+            all.insert(*dep_idx);
+            all.extend(result[*dep_idx].as_ref().unwrap().iter().cloned());
+        }
+        path.pop();
+        result[task_idx] = Some(all);
+        Ok(())
+    }
+
+    let mut result = vec![None; tasks.len()];
+    for task_idx in 0..tasks.len() {
+        if let Err(cycle) = visit(task_idx, &direct, &mut Vec::new(), &mut result) {
+            let names = cycle
+                .iter()
+                .map(|idx| tasks[*idx].name().unwrap_or_default())
+                .collect::<Vec<_>>();
+            return Err(format!(
+                "Task dependencies contain a cycle: {}.",
+                names.join(" -> ")
+            ));
+        }
+    }
+    Ok(result.into_iter().map(Option::unwrap).collect())
+}
+
+/// Everything that can wake up the dispatcher.
+pub enum Event {
+    FileSystem(notify::DebouncedEvent),
+    /// A child process of a running task has exited.
+    ChildExited,
+    /// The user asked us to stop.
+    Interrupt,
+}
+
+/// The tasks from one config file.
+struct TaskGroup {
+    file: PathBuf,
+    tasks: Vec<Box<dyn Task>>,
+    // For each task, the indices of all tasks in this group it (transitively) depends on.
+    dependencies: Vec<HashSet<usize>>,
     // Maps from index into 'tasks' to the current item.
-    work_items: HashMap<usize, Item<'a>>,
+    work_items: HashMap<usize, Item>,
+}
+
+impl TaskGroup {
+    fn interrupt_all(&mut self) {
+        for (_, mut entry) in self.work_items.drain() {
+            if let Some(r) = entry.running_task.take() {
+                r.interrupt();
+            }
+        }
+    }
+
+    /// Hands the runs of tasks that must not be interrupted over to the task with the same name
+    /// in 'new_group', or to 'finishing' if there is none. Restarting tasks are interrupted and
+    /// requested runs are forgotten.
+    fn hand_over_runs(
+        &mut self,
+        mut new_group: Option<&mut TaskGroup>,
+        finishing: &mut Vec<(Option<String>, Box<dyn RunningTask>)>,
+    ) {
+        for (task_idx, mut entry) in self.work_items.drain() {
+            let running_task = match entry.running_task.take() {
+                Some(r) => r,
+                None => continue,
+            };
+            let task = self.tasks[task_idx].as_ref();
+            if task.on_change() == OnChange::Restart {
+                running_task.interrupt();
+                continue;
+            }
+
+            let name = task.name();
+            let new_idx = new_group.as_ref().and_then(|new_group| match name {
+                Some(_) => new_group.tasks.iter().position(|t| t.name() == name),
+                // Unnamed tasks are only recognized by their position.
+                None => new_group
+                    .tasks
+                    .get(task_idx)
+                    .filter(|t| t.name().is_none())
+                    .map(|_| task_idx),
+            });
+            match (new_group.as_mut(), new_idx) {
+                (Some(new_group), Some(new_idx)) => {
+                    new_group.work_items.insert(
+                        new_idx,
+                        Item {
+                            last_run_requested: None,
+                            changed_files: Vec::new(),
+                            running_task: Some(running_task),
+                            waiting_for_slot: false,
+                        },
+                    );
+                }
+                _ => finishing.push((task.exclusive_group(), running_task)),
+            }
+        }
+    }
+}
+
+pub struct ShellGrunt2<'a> {
+    // In the order the config files were added.
+    groups: Vec<TaskGroup>,
+    events_rx: &'a mpsc::Receiver<Event>,
+    // Handed to running tasks, so that they can wake us up.
+    events_tx: mpsc::Sender<Event>,
+    // The maximum number of tasks running at the same time, if limited.
+    max_parallel: Option<usize>,
+    // None if ignore files should not be looked at.
+    ignore_filter: Option<IgnoreFilter>,
+    // What tasks without their own watch roots see.
+    default_roots: Vec<PathBuf>,
+    progress_reporter: Box<dyn ProgressReporter>,
+    next_run_id: u64,
+    // Runs of tasks that were removed while running, with their exclusive group. They still
+    // take up their slot until they are done.
+    finishing: Vec<(Option<String>, Box<dyn RunningTask>)>,
 }
 
 impl<'a> ShellGrunt2<'a> {
+    /// Starts without tasks, they are added per config file through 'set_tasks'.
     pub fn new(
-        tasks: &'a [Box<dyn Task>],
-        events_rx: mpsc::Receiver<notify::DebouncedEvent>,
+        events_rx: &'a mpsc::Receiver<Event>,
+        events_tx: mpsc::Sender<Event>,
+        ignore_filter: Option<IgnoreFilter>,
+        default_roots: Vec<PathBuf>,
     ) -> ShellGrunt2<'a> {
         ShellGrunt2 {
-            tasks,
+            groups: Vec::new(),
             events_rx,
+            events_tx,
+            max_parallel: None,
+            ignore_filter,
+            default_roots,
+            progress_reporter: task::progress_reporter(),
+            next_run_id: 1,
+            finishing: Vec::new(),
+        }
+    }
+
+    /// Replaces the tasks from 'file' with 'tasks', or adds them if there are none yet. Runs of
+    /// old tasks that restart on changes are interrupted, all others are allowed to finish. The
+    /// tasks from other files are not touched. Tasks only depend on tasks from the same file. On
+    /// errors, nothing is changed.
+    pub fn set_tasks(&mut self, file: &Path, tasks: Vec<Box<dyn Task>>) -> Result<(), ConfigError> {
+        // Tasks that are not selected by their tags are left out, but the ones depending on them
+        // are still fine: a task that is not there is never triggered.
+        let task_refs = tasks.iter().map(|task| task.as_ref()).collect::<Vec<_>>();
+        let dependencies = resolve_dependencies(&task_refs, true)
+            .map_err(|message| ConfigError::new(file, message))?;
+        let mut group = TaskGroup {
+            file: file.to_path_buf(),
+            tasks,
+            dependencies,
             work_items: HashMap::new(),
+        };
+        match self.groups.iter_mut().find(|group| group.file == file) {
+            Some(old_group) => {
+                old_group.hand_over_runs(Some(&mut group), &mut self.finishing);
+                *old_group = group;
+            }
+            None => self.groups.push(group),
         }
+        Ok(())
     }
 
+    /// Forgets the tasks from 'file'. Like in 'set_tasks', only restarting tasks are interrupted.
+    pub fn remove_tasks(&mut self, file: &Path) {
+        for group in &mut self.groups {
+            if group.file == file {
+                group.hand_over_runs(None, &mut self.finishing);
+            }
+        }
+        self.groups.retain(|group| group.file != file);
+    }
+
+    pub fn set_max_parallel(&mut self, max_parallel: Option<usize>) {
+        self.max_parallel = max_parallel;
+    }
+
+    /// Blocks until a file changes, a child exits, a start delay runs out or an interrupt is
+    /// requested, then starts all work that is due.
     pub fn spin(&mut self) {
+        let ev = match self.time_until_next_start() {
+            Some(timeout) => self.events_rx.recv_timeout(timeout).ok(),
+            None => self.events_rx.recv().ok(),
+        };
+        if let Some(ev) = ev {
+            self.handle_event(ev);
+        }
         while let Ok(ev) = self.events_rx.try_recv() {
-            use notify::DebouncedEvent::*;
+            self.handle_event(ev);
+        }
 
-            let path = match ev {
-                NoticeWrite(path) | NoticeRemove(path) | Create(path) | Write(path)
-                | Remove(path) => path,
+        // Tasks can finish right when they are started, which might unblock others.
+        while self.check_for_new_work() {}
+    }
 
-                Rename(_, new_path) => new_path,
+    /// Returns how long it is until the start delay of the next requested run runs out. Runs
+    /// that are only blocked by other tasks are not considered, they wait for a child to exit.
+    fn time_until_next_start(&self) -> Option<std::time::Duration> {
+        let now = time::PreciseTime::now();
+        self.groups
+            .iter()
+            .flat_map(|group| {
+                group
+                    .work_items
+                    .iter()
+                    .filter_map(move |(task_idx, entry)| {
+                        let requested = entry.last_run_requested?;
+                        (group.tasks[*task_idx].start_delay() - requested.to(now))
+                            .to_std()
+                            .ok()
+                    })
+            })
+            .min()
+    }
 
-                Error(err, path) => {
-                    println!("Ignored error: {:?}, ({:?})", err, path);
+    fn handle_event(&mut self, ev: Event) {
+        use notify::DebouncedEvent::*;
+
+        let ev = match ev {
+            Event::FileSystem(ev) => ev,
+            Event::ChildExited | Event::Interrupt => return,
+        };
+        let event = match ev {
+            Create(path) => FileEvent {
+                path,
+                kind: EventKind::Create,
+                from: None,
+            },
+            Write(path) => FileEvent {
+                path,
+                kind: EventKind::Write,
+                from: None,
+            },
+            Remove(path) => FileEvent {
+                path,
+                kind: EventKind::Remove,
+                from: None,
+            },
+            Rename(old_path, new_path) => FileEvent {
+                path: new_path,
+                kind: EventKind::Rename,
+                from: Some(old_path),
+            },
+
+            Error(err, path) => {
+                println!("Ignored error: {:?}, ({:?})", err, path);
+                return;
+            }
+            // Notices are always followed by a 'Write' or 'Remove' once the file settled. Reacting
+            // to both would start tasks twice now that we no longer poll.
+            NoticeWrite(_) | NoticeRemove(_) | Rescan | Chmod(_) => return,
+        };
+
+        let is_ignored = match self.ignore_filter.as_mut() {
+            Some(ignore_filter) => {
+                ignore_filter.path_changed(&event.path);
+                ignore_filter.is_ignored(&event.path)
+            }
+            None => false,
+        };
+        for group in &mut self.groups {
+            for (task_idx, task) in group.tasks.iter().enumerate() {
+                if is_ignored && task.respect_gitignore() {
                     continue;
                 }
-                Rescan | Chmod(_) => continue,
-            };
-            for (task_idx, task) in self.tasks.iter().enumerate() {
-                if !task.should_run(&path) {
+                let roots = task.watch_roots();
+                let roots = if roots.is_empty() {
+                    &self.default_roots
+                } else {
+                    roots
+                };
+                if !roots.iter().any(|root| event.path.starts_with(root)) {
+                    continue;
+                }
+                if !task.should_run(&event) {
                     continue;
                 }
 
-                let entry = self.work_items.entry(task_idx).or_insert(Item {
+                let entry = group.work_items.entry(task_idx).or_insert(Item {
                     last_run_requested: None,
-                    task: task.borrow(),
+                    changed_files: Vec::new(),
                     running_task: None,
+                    waiting_for_slot: false,
                 });
+                if task.on_change() == OnChange::Ignore && entry.is_running() {
+                    continue;
+                }
                 entry.last_run_requested = Some(time::PreciseTime::now());
-                entry.task = task.borrow();
+                if !entry.changed_files.contains(&event.path) {
+                    entry.changed_files.push(event.path.clone());
+                }
             }
         }
+    }
 
-        self.check_for_new_work();
+    /// Interrupts all running tasks and forgets about requested runs.
+    pub fn interrupt_all(&mut self) {
+        for group in &mut self.groups {
+            group.interrupt_all();
+        }
+        for (_, r) in self.finishing.drain(..) {
+            r.interrupt();
+        }
     }
 
-    fn check_for_new_work(&mut self) {
-        let mut done = HashSet::new();
+    /// Starts all runs that are due. Returns true if any run was started.
+    fn check_for_new_work(&mut self) -> bool {
+        self.finishing.retain_mut(|(_, r)| !r.done());
 
+        // Tasks are identified by the indices of their group and of them in it.
+        let mut busy = HashSet::new();
+        // Maps from running tasks to their exclusive group.
+        let mut running = HashMap::new();
         let now = time::PreciseTime::now();
-        for (task_idx, entry) in &mut self.work_items {
-            if entry.last_run_requested.is_some()
-                && entry.last_run_requested.unwrap().to(now) > entry.task.start_delay()
-            {
-                entry.running_task.take().map(|r| r.interrupt());
-                entry.running_task = Some(entry.task.run());
-                entry.last_run_requested = None;
-                continue;
+        for (group_idx, group) in self.groups.iter_mut().enumerate() {
+            for (task_idx, entry) in &mut group.work_items {
+                let id = (group_idx, *task_idx);
+                let is_running = entry.is_running();
+                // Waiting for idle starts over once the previous run is done. Forgetting about the
+                // run makes sure that this happens only once.
+                if !is_running
+                    && entry.running_task.is_some()
+                    && entry.last_run_requested.is_some()
+                    && group.tasks[*task_idx].on_change() == OnChange::WaitForIdle
+                {
+                    entry.running_task = None;
+                    entry.last_run_requested = Some(now);
+                }
+                if is_running {
+                    running.insert(id, group.tasks[*task_idx].exclusive_group());
+                }
+                if is_running || entry.last_run_requested.is_some() {
+                    busy.insert(id);
+                }
             }
+        }
+
+        let mut started_any = false;
+        for (group_idx, group) in self.groups.iter_mut().enumerate() {
+            // Go in order of the tasks, so that earlier tasks get free slots first.
+            let mut task_indices = group.work_items.keys().cloned().collect::<Vec<_>>();
+            task_indices.sort_unstable();
+
+            for task_idx in task_indices {
+                let id = (group_idx, task_idx);
+                let task = group.tasks[task_idx].as_ref();
+                let entry = group.work_items.get_mut(&task_idx).unwrap();
+                match entry.last_run_requested {
+                    Some(requested) if requested.to(now) > task.start_delay() => (),
+                    _ => continue,
+                }
+
+                // Tasks we depend on that are waiting or running go first.
+                if group.dependencies[task_idx]
+                    .iter()
+                    .any(|dep_idx| busy.contains(&(group_idx, *dep_idx)))
+                {
+                    continue;
+                }
+
+                if matches!(task.on_change(), OnChange::Queue | OnChange::WaitForIdle)
+                    && running.contains_key(&id)
+                {
+                    continue;
+                }
+
+                // Restarting a task frees up its own slot, so only the others count.
+                let exclusive_group = task.exclusive_group();
+                let mut num_others = self.finishing.len();
+                let mut group_taken = exclusive_group.is_some()
+                    && self.finishing.iter().any(|(g, _)| *g == exclusive_group);
+                for (other_id, other_group) in &running {
+                    if *other_id == id {
+                        continue;
+                    }
+                    num_others += 1;
+                    group_taken |= exclusive_group.is_some() && *other_group == exclusive_group;
+                }
+                if group_taken || self.max_parallel.is_some_and(|max| num_others >= max) {
+                    if !entry.waiting_for_slot {
+                        self.progress_reporter
+                            .waiting_for_slot(&display_name(task, task_idx));
+                        entry.waiting_for_slot = true;
+                    }
+                    continue;
+                }
 
-            if entry.running_task.is_some() && entry.running_task.as_mut().unwrap().done() {
-                done.insert(task_idx.clone());
+                if let Some(r) = entry.running_task.take() {
+                    r.interrupt();
+                }
+                let context = RunContext {
+                    task_name: display_name(task, task_idx),
+                    run_id: self.next_run_id,
+                    changed_files: std::mem::take(&mut entry.changed_files),
+                    events_tx: self.events_tx.clone(),
+                };
+                self.next_run_id += 1;
+                entry.running_task = Some(task.run(&context));
+                entry.last_run_requested = None;
+                entry.waiting_for_slot = false;
+                running.insert(id, exclusive_group);
+                started_any = true;
             }
-        }
 
-        for key in &done {
-            self.work_items.remove(key);
+            group
+                .work_items
+                .retain(|task_idx, _| busy.contains(&(group_idx, *task_idx)));
         }
+        started_any
     }
 }
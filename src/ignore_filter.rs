@@ -0,0 +1,94 @@
+use ignore::gitignore::Gitignore;
+use ignore::Match;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// In order of precedence.
+const IGNORE_FILE_NAMES: [&str; 2] = [".ignore", ".gitignore"];
+
+/// Decides whether a changed path is ignored by '.ignore' or '.gitignore' files in its parent
+/// directories or by the global git excludes. Ignore files are only read once and forgotten again
+/// when they change.
+pub struct IgnoreFilter {
+    global: Gitignore,
+    // Maps from directory to the ignore files found in it.
+    by_dir: HashMap<PathBuf, Vec<Gitignore>>,
+}
+
+impl IgnoreFilter {
+    pub fn new() -> Self {
+        let (global, err) = Gitignore::global();
+        if let Some(err) = err {
+            println!("Ignored error while reading global git excludes: {}", err);
+        }
+        IgnoreFilter {
+            global,
+            by_dir: HashMap::new(),
+        }
+    }
+
+    pub fn is_ignored(&mut self, path: &Path) -> bool {
+        // Git's own bookkeeping is never interesting.
+        if path.components().any(|c| c.as_os_str() == ".git") {
+            return true;
+        }
+
+        // Files that are gone are treated as files, we cannot know better.
+        let is_dir = path.is_dir();
+        let mut root = Path::new("/");
+        // Deeper ignore files take precedence, so walk upwards up to the root of the repository.
+        for dir in path.ancestors().skip(1) {
+            for ignore_file in self.ignore_files(dir) {
+                match ignore_file.matched_path_or_any_parents(path, is_dir) {
+                    Match::None => (),
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                }
+            }
+            root = dir;
+            if dir.join(".git").exists() {
+                break;
+            }
+        }
+
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        self.global
+            .matched_path_or_any_parents(relative, is_dir)
+            .is_ignore()
+    }
+
+    /// Must be called for every changed path, so that changed ignore files are read again.
+    pub fn path_changed(&mut self, path: &Path) {
+        let is_ignore_file = path
+            .file_name()
+            .is_some_and(|name| IGNORE_FILE_NAMES.iter().any(|n| name == *n));
+        if is_ignore_file {
+            if let Some(dir) = path.parent() {
+                self.by_dir.remove(dir);
+            }
+        }
+    }
+
+    fn ignore_files(&mut self, dir: &Path) -> &[Gitignore] {
+        self.by_dir.entry(dir.to_path_buf()).or_insert_with(|| {
+            IGNORE_FILE_NAMES
+                .iter()
+                .map(|name| dir.join(name))
+                .filter(|path| path.is_file())
+                .map(|path| {
+                    let (gitignore, err) = Gitignore::new(&path);
+                    if let Some(err) = err {
+                        println!("Ignored error in {}: {}", path.display(), err);
+                    }
+                    gitignore
+                })
+                .collect()
+        })
+    }
+}
+
+impl Default for IgnoreFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
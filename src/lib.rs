@@ -1,7 +1,13 @@
+pub mod config;
 pub mod dispatch;
+pub mod ignore_filter;
 pub mod lockfile;
+pub mod lua_grunt;
+pub mod lua_path;
 pub mod lua_task;
+pub mod path_filter;
 pub mod task;
+pub mod toml_task;
 
-pub use crate::dispatch::ShellGrunt2;
+pub use crate::dispatch::{Event, ShellGrunt2};
 pub use crate::task::Task;
@@ -0,0 +1,162 @@
+use lua;
+
+use self::lua::ffi::lua_State;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Key in the Lua registry under which the directory of the config file is stored.
+const CONFIG_DIR_KEY: &str = "shell_grunt2.config_dir";
+
+/// Pushes 'nil' and 'message', the usual way for Lua functions to report errors.
+fn push_error(state: &mut lua::State, message: &str) -> libc::c_int {
+    state.push_nil();
+    state.push_string(message);
+    2
+}
+
+fn current_dir() -> PathBuf {
+    env::current_dir().unwrap_or_default()
+}
+
+/// grunt.glob(pattern) -> list of matching paths, sorted.
+#[allow(non_snake_case)]
+unsafe extern "C" fn glob(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let pattern = state.check_string(1).to_string();
+    let paths = match glob::glob(&pattern) {
+        Ok(paths) => paths,
+        Err(err) => return push_error(&mut state, &format!("Invalid glob '{}': {}", pattern, err)),
+    };
+    state.new_table();
+    // Paths we cannot read are skipped, like the shell does.
+    for (idx, path) in paths.filter_map(Result::ok).enumerate() {
+        state.push_string(&path.to_string_lossy());
+        state.raw_seti(-2, idx as i64 + 1);
+    }
+    1
+}
+
+/// grunt.exists(path) -> true if there is a file or directory at 'path'.
+#[allow(non_snake_case)]
+unsafe extern "C" fn exists(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let exists = Path::new(state.check_string(1)).exists();
+    state.push_bool(exists);
+    1
+}
+
+/// grunt.read_file(path) -> contents of the file, or nil and an error message.
+#[allow(non_snake_case)]
+unsafe extern "C" fn read_file(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let path = state.check_string(1).to_string();
+    match fs::read_to_string(&path) {
+        Ok(contents) => {
+            state.push_string(&contents);
+            1
+        }
+        Err(err) => push_error(&mut state, &format!("{}: {}", path, err)),
+    }
+}
+
+/// grunt.exec(command) -> exit code and standard output of 'command' run through 'sh -c'. The
+/// standard error goes to our terminal.
+#[allow(non_snake_case)]
+unsafe extern "C" fn exec(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let command = state.check_string(1).to_string();
+    let output = match process::Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stderr(process::Stdio::inherit())
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => return push_error(&mut state, &format!("{}: {}", command, err)),
+    };
+    // Like the shell, report commands killed by a signal as 128 + signal.
+    let code = output.status.code().unwrap_or_else(|| {
+        128 + std::os::unix::process::ExitStatusExt::signal(&output.status).unwrap_or(0)
+    });
+    state.push_integer(code.into());
+    state.push_string(&String::from_utf8_lossy(&output.stdout));
+    2
+}
+
+/// grunt.getenv(name) -> value of the environment variable, or nil if it is not set.
+#[allow(non_snake_case)]
+unsafe extern "C" fn getenv(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    match env::var(state.check_string(1)) {
+        Ok(value) => state.push_string(&value),
+        Err(_) => state.push_nil(),
+    }
+    1
+}
+
+/// grunt.relpath(path) -> 'path' relative to the current directory.
+#[allow(non_snake_case)]
+unsafe extern "C" fn relpath(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let path = PathBuf::from(state.check_string(1));
+    let relative = if path.is_absolute() {
+        pathdiff::diff_paths(&path, &current_dir()).unwrap_or(path)
+    } else {
+        path
+    };
+    state.push_string(&relative.to_string_lossy());
+    1
+}
+
+/// grunt.cwd() -> the current directory, which is also where commands run.
+#[allow(non_snake_case)]
+unsafe extern "C" fn cwd(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    state.push_string(&current_dir().to_string_lossy());
+    1
+}
+
+/// grunt.config_dir() -> the directory containing the config file.
+#[allow(non_snake_case)]
+unsafe extern "C" fn config_dir(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    state.get_field(lua::REGISTRYINDEX, CONFIG_DIR_KEY);
+    1
+}
+
+/// grunt.log(message) prints 'message' between the output of our commands.
+#[allow(non_snake_case)]
+unsafe extern "C" fn log(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    println!("==> {}", state.check_string(1));
+    0
+}
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn open(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    state.new_lib(&[
+        ("glob", Some(glob)),
+        ("exists", Some(exists)),
+        ("read_file", Some(read_file)),
+        ("exec", Some(exec)),
+        ("getenv", Some(getenv)),
+        ("relpath", Some(relpath)),
+        ("cwd", Some(cwd)),
+        ("config_dir", Some(config_dir)),
+        ("log", Some(log)),
+    ]);
+    1
+}
+
+/// Makes the 'grunt' module available to the config, both as a global and through
+/// 'require("grunt")'.
+pub fn inject(state: &mut lua::State, config_dir: &Path) {
+    state.push_string(&config_dir.to_string_lossy());
+    state.set_field(lua::REGISTRYINDEX, CONFIG_DIR_KEY);
+
+    state.requiref("grunt", Some(open), true); // S: <grunt>
+    state.pop(1);
+}
@@ -0,0 +1,188 @@
+use lua;
+
+use self::lua::ffi::lua_State;
+use globset::GlobBuilder;
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+// All functions return nil when there is no result rather than nothing, so that they can always be
+// passed on, e.g. 'table.insert(extensions, path:ext())' fails on nothing.
+
+fn push_optional_path(state: &mut lua::State, path: Option<&Path>) -> libc::c_int {
+    match path {
+        Some(path) => state.push_string(&path.to_string_lossy()),
+        None => state.push_nil(),
+    }
+    1
+}
+
+/// Returns the first 'count' arguments as strings. A bad argument raises a Lua error, which jumps
+/// over the calling Rust frames without dropping them, so all are checked before anything is
+/// allocated.
+fn check_strings(state: &mut lua::State, count: lua::Index) -> Vec<String> {
+    for idx in 1..=count {
+        state.check_string(idx);
+    }
+    (1..=count)
+        .map(|idx| state.to_str_in_place(idx).unwrap_or_default().to_string())
+        .collect()
+}
+
+/// Makes 'path' absolute and removes '.' and '..' from it, without touching the file system, so
+/// that it also works for files that were just removed.
+fn absolute(path: &Path) -> PathBuf {
+    let path = env::current_dir().unwrap_or_default().join(path);
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                result.pop();
+            }
+            _ => result.push(component),
+        }
+    }
+    result
+}
+
+/// path:ext() -> the extension without the '.'.
+#[allow(non_snake_case)]
+unsafe extern "C" fn ext(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let string: String = state.check_string(1).to_string();
+    let p = Path::new(&string);
+    push_optional_path(&mut state, p.extension().map(Path::new))
+}
+
+/// path:basename() -> the last component.
+#[allow(non_snake_case)]
+unsafe extern "C" fn basename(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let string: String = state.check_string(1).to_string();
+    let p = Path::new(&string);
+    push_optional_path(&mut state, p.file_name().map(Path::new))
+}
+
+/// path:dirname() -> everything but the last component.
+#[allow(non_snake_case)]
+unsafe extern "C" fn dirname(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let string: String = state.check_string(1).to_string();
+    let p = Path::new(&string);
+    push_optional_path(&mut state, p.parent())
+}
+
+/// path:stem() -> the last component without the extension.
+#[allow(non_snake_case)]
+unsafe extern "C" fn stem(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let string: String = state.check_string(1).to_string();
+    let p = Path::new(&string);
+    push_optional_path(&mut state, p.file_stem().map(Path::new))
+}
+
+/// path:relpath([base]) -> 'path' relative to 'base', which defaults to the current directory.
+#[allow(non_snake_case)]
+unsafe extern "C" fn relpath(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let count = if state.is_none_or_nil(2) { 1 } else { 2 };
+    let args = check_strings(&mut state, count);
+    let path = absolute(Path::new(&args[0]));
+    let base = absolute(Path::new(args.get(1).map_or(".", String::as_str)));
+    let relative = pathdiff::diff_paths(&path, &base);
+    push_optional_path(&mut state, relative.as_deref())
+}
+
+/// path:join(...) -> 'path' with all arguments appended as components.
+#[allow(non_snake_case)]
+unsafe extern "C" fn join(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let count = state.get_top().max(1);
+    let path = check_strings(&mut state, count).iter().collect::<PathBuf>();
+    push_optional_path(&mut state, Some(&path))
+}
+
+/// path:split() -> a list of the components of 'path'.
+#[allow(non_snake_case)]
+unsafe extern "C" fn split(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let string: String = state.check_string(1).to_string();
+    state.new_table();
+    for (idx, component) in Path::new(&string).components().enumerate() {
+        state.push_string(&component.as_os_str().to_string_lossy());
+        state.raw_seti(-2, idx as i64 + 1);
+    }
+    1
+}
+
+/// path:matches(glob) -> true if 'path' matches 'glob', or nil and an error message if 'glob' is
+/// invalid. Like for 'include', '*' does not match '/'.
+#[allow(non_snake_case)]
+unsafe extern "C" fn matches(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let args = check_strings(&mut state, 2);
+    let (path, pattern) = (&args[0], &args[1]);
+    match GlobBuilder::new(pattern).literal_separator(true).build() {
+        Ok(glob) => {
+            state.push_bool(glob.compile_matcher().is_match(path));
+            1
+        }
+        Err(err) => {
+            state.push_nil();
+            state.push_string(&format!("Invalid glob '{}': {}", pattern, err));
+            2
+        }
+    }
+}
+
+/// path:is_under(dir) -> true if 'path' is 'dir' or inside of it.
+#[allow(non_snake_case)]
+unsafe extern "C" fn is_under(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let args = check_strings(&mut state, 2);
+    let path = absolute(Path::new(&args[0]));
+    let dir = absolute(Path::new(&args[1]));
+    state.push_bool(path.starts_with(dir));
+    1
+}
+
+/// path:is_dir() -> true if there is a directory at 'path'.
+#[allow(non_snake_case)]
+unsafe extern "C" fn is_dir(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let is_dir = Path::new(state.check_string(1)).is_dir();
+    state.push_bool(is_dir);
+    1
+}
+
+/// path:abspath() -> 'path' made absolute against the current directory.
+#[allow(non_snake_case)]
+unsafe extern "C" fn abspath(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let path = absolute(Path::new(state.check_string(1)));
+    push_optional_path(&mut state, Some(&path))
+}
+
+/// Adds the path functions to the 'string' table, so that they can be called like
+/// 'path:basename()'.
+pub fn inject(state: &mut lua::State) {
+    state.get_global("string"); // S: <string>
+    let functions: &[(&str, lua::Function)] = &[
+        ("ext", Some(ext)),
+        ("basename", Some(basename)),
+        ("dirname", Some(dirname)),
+        ("stem", Some(stem)),
+        ("relpath", Some(relpath)),
+        ("join", Some(join)),
+        ("split", Some(split)),
+        ("matches", Some(matches)),
+        ("is_under", Some(is_under)),
+        ("is_dir", Some(is_dir)),
+        ("abspath", Some(abspath)),
+    ];
+    for (name, function) in functions {
+        state.push_fn(*function);
+        state.set_field(-2, name);
+    }
+    state.pop(1);
+}
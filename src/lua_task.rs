@@ -3,11 +3,23 @@ use lua;
 use time;
 
 use self::lua::ffi::lua_State;
-use crate::task::{ShellCommand, ShellTask, Task};
-use std::cell::RefCell;
+use crate::config::{self, CheckedFile, Config, ConfigError};
+use crate::dispatch;
+use crate::lua_grunt;
+use crate::lua_path;
+use crate::path_filter::PathFilter;
+use crate::task::{
+    self, EventKind, FileEvent, Hook, OnChange, RunHooks, RunResult, ShellCommand, ShellTask, Task,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::env;
+use std::fs;
 use std::path;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 // TODO(sirver): This whole file is quite the hack. If a LuaDictionary would get a proper
 // abstraction, this could be expressed more tightly. This is a bit tricky with the correct
@@ -49,14 +61,801 @@ fn pop_bool(state: &mut lua::State) -> Option<bool> {
     rv
 }
 
+fn pop_string_list(state: &mut lua::State) -> Vec<String> {
+    let mut result = Vec::new();
+    if state.is_table(-1) {
+        state.push_nil(); // S: <list> nil
+        while state.next(-2) {
+            // S: <list> key value
+            if let Some(value) = pop_string(state) {
+                result.push(value);
+            }
+        }
+    }
+    state.pop(1);
+    result
+}
+
+/// Pushes a table like '{path = ..., kind = "rename", from = ...}' describing 'event'.
+fn push_event(event: &FileEvent, state: &mut lua::State) {
+    state.new_table(); // S: <event>
+    state.push_string(&event.path.to_string_lossy());
+    state.set_field(-2, "path");
+    state.push_string(event.kind.name());
+    state.set_field(-2, "kind");
+    if let Some(from) = &event.from {
+        state.push_string(&from.to_string_lossy());
+        state.set_field(-2, "from");
+    }
+}
+
+/// Pushes a list with all 'paths'.
+fn push_path_list(paths: &[path::PathBuf], state: &mut lua::State) {
+    state.new_table(); // S: <list>
+    for (idx, path) in paths.iter().enumerate() {
+        state.push_string(&path.to_string_lossy());
+        state.raw_seti(-2, idx as i64 + 1);
+    }
+}
+
+/// Pushes a table describing 'result' for the hooks of a task.
+fn push_run_result(result: &RunResult, state: &mut lua::State) {
+    state.new_table(); // S: <result>
+    state.push_string(&result.task_name);
+    state.set_field(-2, "task");
+    if let Some(command) = &result.command {
+        state.push_string(command);
+        state.set_field(-2, "command");
+    }
+    if let Some(exit_code) = result.exit_code {
+        state.push_integer(exit_code.into());
+        state.set_field(-2, "exit_code");
+    }
+    state.push_number(result.duration.as_secs_f64());
+    state.set_field(-2, "duration");
+    state.push_bool(result.success);
+    state.set_field(-2, "success");
+    state.push_bool(result.cancelled);
+    state.set_field(-2, "cancelled");
+    push_path_list(&result.changed_files, state); // S: <result> <changed_files>
+    state.set_field(-2, "changed_files"); // S: <result>
+    state.push_string(&result.stderr_tail.join("\n"));
+    state.set_field(-2, "stderr");
+}
+
+/// Turns a Lua error message, which usually starts with 'file:line:', into a ConfigError.
+fn lua_error(file: &path::Path, message: &str) -> ConfigError {
+    lazy_static! {
+        static ref LOCATION: Regex = Regex::new(r"(?s)^(.+?):(\d+): (.*)$").unwrap();
+    }
+    // Errors in files loaded through 'require' look like "error loading module 'name' from file
+    // 'file':\n\tfile:line: message", the interesting part is the last.
+    let message = message.rsplit("\n\t").next().unwrap_or(message);
+    let captures = match LOCATION.captures(message) {
+        Some(captures) => captures,
+        None => return ConfigError::new(file, message),
+    };
+    // Lua shortens long file names to '...end/of/the/path'.
+    let file_utf8 = file.to_string_lossy();
+    let file = match captures[1].strip_prefix("...") {
+        Some(end) if file_utf8.ends_with(end) => file.to_path_buf(),
+        _ => path::PathBuf::from(&captures[1]),
+    };
+    ConfigError {
+        file,
+        line: captures[2].parse().ok(),
+        task: None,
+        message: captures[3].to_string(),
+    }
+}
+
+/// Limits for a single call of a Lua callback, so that an endless loop in the config cannot freeze
+/// us. Configured through the 'callback_timeout' (in milliseconds) and 'callback_instructions'
+/// settings.
+#[derive(Clone, Copy)]
+struct CallbackBudget {
+    // None if the callback may take as long as it likes.
+    timeout: Option<Duration>,
+    instructions: i64,
+}
+
+impl Default for CallbackBudget {
+    fn default() -> Self {
+        CallbackBudget {
+            timeout: Some(Duration::from_millis(1000)),
+            instructions: 100_000_000,
+        }
+    }
+}
+
+impl CallbackBudget {
+    /// Only 'should_run' blocks the dispatcher for every event. The other callbacks may wait for
+    /// host calls like 'grunt.exec' as long as these take, so only their instructions are limited.
+    fn without_timeout(self) -> Self {
+        CallbackBudget {
+            timeout: None,
+            ..self
+        }
+    }
+}
+
+// The debug hook checks the budget every this many instructions.
+const BUDGET_CHECK_INTERVAL: i64 = 1000;
+
+// Key in the Lua registry of the function returned by BUDGET_HOOK.
+const SET_BUDGET_HOOK_KEY: &str = "shell_grunt2.set_budget_hook";
+
+// Called with 'budget_exceeded' and BUDGET_CHECK_INTERVAL, returns a function that installs the
+// hook. The hook is a Lua function, so that the error is raised by Lua: raising it from a hook
+// written in Rust would unwind through Rust frames.
+const BUDGET_HOOK: &str = r#"
+local sethook, error = debug.sethook, error
+local exceeded, interval = ...
+
+local function hook()
+  if exceeded() then
+    -- From now on on every instruction, so that a 'pcall' in the callback cannot catch them all.
+    sethook(hook, "", 1)
+    error("Callback exceeded its budget.", 0)
+  end
+end
+
+return function() sethook(hook, "", interval) end
+"#;
+
+// What is left of the budget of the running callback, kept in the extra space of the state.
+struct RemainingBudget {
+    deadline: Option<Instant>,
+    instructions: i64,
+    exceeded: bool,
+}
+
+/// budget_exceeded() -> true once the running callback used up its budget.
+#[allow(non_snake_case)]
+unsafe extern "C" fn budget_exceeded(L: *mut lua_State) -> libc::c_int {
+    let mut state = lua::State::from_ptr(L);
+    let exceeded = state.with_extra(|extra| {
+        match extra
+            .as_mut()
+            .and_then(|extra| extra.downcast_mut::<RemainingBudget>())
+        {
+            Some(budget) => {
+                budget.instructions -= BUDGET_CHECK_INTERVAL;
+                budget.exceeded = budget.instructions <= 0
+                    || budget
+                        .deadline
+                        .is_some_and(|deadline| Instant::now() >= deadline);
+                budget.exceeded
+            }
+            None => false,
+        }
+    });
+    state.push_bool(exceeded);
+    1
+}
+
+fn setup_budget_hook(state: &mut lua::State) {
+    let status = state.load_buffer(BUDGET_HOOK.as_bytes(), "=budget_hook");
+    assert!(!status.is_err(), "BUDGET_HOOK does not compile.");
+    state.push_fn(Some(budget_exceeded));
+    state.push_integer(BUDGET_CHECK_INTERVAL);
+    state.call(2, 1); // S: set_hook
+    state.set_field(lua::REGISTRYINDEX, SET_BUDGET_HOOK_KEY);
+}
+
+/// Why a callback did not return.
+enum CallbackError {
+    Lua(String),
+    OverBudget,
+}
+
+/// Calls the function below the 'nargs' arguments at the top of the stack like 'pcall', but aborts
+/// it once it exceeds 'budget'. On errors, the error message is popped from the stack.
+fn call_with_budget(
+    state: &mut lua::State,
+    budget: CallbackBudget,
+    nargs: i32,
+    nresults: i32,
+) -> Result<(), CallbackError> {
+    state.set_extra(Some(Box::new(RemainingBudget {
+        deadline: budget.timeout.map(|timeout| Instant::now() + timeout),
+        instructions: budget.instructions,
+        exceeded: false,
+    })));
+    state.get_field(lua::REGISTRYINDEX, SET_BUDGET_HOOK_KEY);
+    state.call(0, 0);
+    let status = state.pcall(nargs, nresults, 0);
+    // Not through Lua, the hook would fire again.
+    state.set_hook(None, lua::HookMask::empty(), 0);
+    let exceeded = state
+        .set_extra(None)
+        .and_then(|extra| extra.downcast::<RemainingBudget>().ok())
+        .is_some_and(|budget| budget.exceeded);
+    if !status.is_err() {
+        return Ok(());
+    }
+    let message = state.to_str_in_place(-1).unwrap_or_default().to_string();
+    state.pop(1);
+    if exceeded {
+        Err(CallbackError::OverBudget)
+    } else {
+        Err(CallbackError::Lua(message))
+    }
+}
+
+/// Turns the failure of the 'callback' of 'task' into an error to show. A callback that exceeds
+/// its budget marks the task as misconfigured, its callbacks are not called again.
+fn callback_error(
+    err: CallbackError,
+    callback: &str,
+    file: &path::Path,
+    task: &str,
+    budget: CallbackBudget,
+    misconfigured: &Cell<bool>,
+) -> ConfigError {
+    match err {
+        CallbackError::Lua(message) => lua_error(file, &message).with_task(task.to_string()),
+        CallbackError::OverBudget => {
+            misconfigured.set(true);
+            let limits = match budget.timeout {
+                Some(timeout) => format!(
+                    "{} ms or {} instructions",
+                    timeout.as_millis(),
+                    budget.instructions
+                ),
+                None => format!("{} instructions", budget.instructions),
+            };
+            ConfigError::new(
+                file,
+                format!(
+                    "'{}' did not finish within {}. The task is disabled until the config is \
+                     reloaded.",
+                    callback, limits
+                ),
+            )
+            .with_task(task.to_string())
+        }
+    }
+}
+
+/// Reads the command at the top of the stack, leaving the stack as it was. A command is either
+/// the command line itself or a table with 'command' and optionally 'name' and 'work_directory'.
+/// The command line doubles as the name if there is none.
+fn read_command(state: &mut lua::State) -> Result<ShellCommand, String> {
+    if state.is_string(-1) {
+        let command = state.check_string(-1).to_string();
+        return Ok(ShellCommand {
+            name: command.clone(),
+            command,
+            work_directory: None,
+        });
+    }
+    if !state.is_table(-1) {
+        return Err("Expected every command to be a string or a table.".to_string());
+    }
+    get_value_in_dict("command", state);
+    let command = pop_string(state).ok_or("Expected 'command' in every command.")?;
+    get_value_in_dict("name", state);
+    let name = pop_string(state).unwrap_or_else(|| command.clone());
+    get_value_in_dict("work_directory", state);
+    let work_directory = pop_string(state).map(path::PathBuf::from);
+    Ok(ShellCommand {
+        name,
+        command,
+        work_directory,
+    })
+}
+
+/// Reads the table of commands at the top of the stack, leaving the stack as it was.
+fn read_commands(state: &mut lua::State) -> Result<Vec<ShellCommand>, String> {
+    if !state.is_table(-1) {
+        return Err("Expected 'command' or a table of 'commands'.".to_string());
+    }
+    let mut result = Vec::new();
+    state.push_nil(); // S: <commands> nil
+    while state.next(-2) {
+        // S: <commands> key value
+        let command = read_command(state);
+        state.pop(1); // S: <commands> key
+        match command {
+            Ok(command) => result.push(command),
+            Err(err) => {
+                state.pop(1);
+                return Err(err);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Reads the 'profiles' setting at the top of the stack, leaving the stack as it was. It maps
+/// profile names to lists of tags.
+fn read_profiles(state: &mut lua::State) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut profiles = HashMap::new();
+    if state.is_nil(-1) {
+        return Ok(profiles);
+    }
+    let error = "Expected 'profiles' to map names to lists of tags.";
+    if !state.is_table(-1) {
+        return Err(error.to_string());
+    }
+    state.push_nil(); // S: <profiles> nil
+    while state.next(-2) {
+        // S: <profiles> key value
+        if state.type_of(-2) != Some(lua::Type::String) || !state.is_table(-1) {
+            state.pop(2);
+            return Err(error.to_string());
+        }
+        let tags = pop_string_list(state); // S: <profiles> key
+        let name = state.to_str_in_place(-1).unwrap_or_default().to_string();
+        profiles.insert(name, tags);
+    }
+    Ok(profiles)
+}
+
+/// The types a value in a task table can have.
+#[derive(Clone, Copy)]
+enum ValueType {
+    String,
+    Integer,
+    Bool,
+    Table,
+    Function,
+}
+
+impl ValueType {
+    fn name(self) -> &'static str {
+        match self {
+            ValueType::String => "a string",
+            ValueType::Integer => "an integer",
+            ValueType::Bool => "a boolean",
+            ValueType::Table => "a table",
+            ValueType::Function => "a function",
+        }
+    }
+
+    fn matches(self, state: &mut lua::State, idx: lua::Index) -> bool {
+        match self {
+            // Lua would happily turn numbers into strings, but that is most likely a mistake.
+            ValueType::String => state.type_of(idx) == Some(lua::Type::String),
+            ValueType::Integer => state.is_integer(idx),
+            ValueType::Bool => state.is_bool(idx),
+            ValueType::Table => state.is_table(idx),
+            ValueType::Function => state.is_fn(idx),
+        }
+    }
+}
+
+// All keys a task table can have, with the types their values can have.
+const TASK_SCHEMA: &[(&str, &[ValueType])] = &[
+    ("name", &[ValueType::String]),
+    ("should_run", &[ValueType::Function]),
+    ("command", &[ValueType::String, ValueType::Table]),
+    ("commands", &[ValueType::Table, ValueType::Function]),
+    ("environment", &[ValueType::Table]),
+    ("redirect_stdout", &[ValueType::String]),
+    ("redirect_stderr", &[ValueType::String]),
+    ("suppress_stdout", &[ValueType::Bool]),
+    ("suppress_stderr", &[ValueType::Bool]),
+    ("start_delay", &[ValueType::Integer]),
+    ("stop_signal", &[ValueType::String, ValueType::Integer]),
+    ("stop_timeout", &[ValueType::Integer]),
+    ("depends_on", &[ValueType::Table]),
+    ("on_change", &[ValueType::String]),
+    ("respect_gitignore", &[ValueType::Bool]),
+    ("exclusive_group", &[ValueType::String]),
+    ("persist_state", &[ValueType::Bool]),
+    ("tags", &[ValueType::Table]),
+    ("watch", &[ValueType::Table]),
+    ("events", &[ValueType::Table]),
+    ("include", &[ValueType::Table]),
+    ("exclude", &[ValueType::Table]),
+    ("on_start", &[ValueType::Function]),
+    ("on_success", &[ValueType::Function]),
+    ("on_failure", &[ValueType::Function]),
+    ("on_finish", &[ValueType::Function]),
+];
+
+// All global settings that can be next to the tasks.
+const SETTINGS: &[&str] = &[
+    "max_parallel",
+    "callback_timeout",
+    "callback_instructions",
+    "profiles",
+    "configs",
+];
+
+// Keys in the Lua registry of the table with the 'state' tables of all tasks, indexed by task key,
+// and of the functions returned by STATE_SERIALIZATION.
+const TASK_STATES_KEY: &str = "shell_grunt2.task_states";
+const SERIALIZE_STATE_KEY: &str = "shell_grunt2.serialize_state";
+const DESERIALIZE_STATE_KEY: &str = "shell_grunt2.deserialize_state";
+
+// Returns functions that turn a 'state' table into Lua source and back. Only values that can be
+// written as literals can be saved.
+const STATE_SERIALIZATION: &str = r#"
+local function serialize(value, seen)
+  local kind = type(value)
+  if kind == "string" then
+    return string.format("%q", value)
+  elseif kind == "number" then
+    if math.type(value) == "integer" then return tostring(value) end
+    if value ~= value then return "0/0" end
+    if value == math.huge then return "1/0" end
+    if value == -math.huge then return "-1/0" end
+    local text = string.format("%.17g", value)
+    -- Keep floats floats when they are read back.
+    if not text:find("[.e]") then text = text .. ".0" end
+    return text
+  elseif kind == "boolean" then
+    return tostring(value)
+  elseif kind == "table" then
+    if seen[value] then error("Cannot save a table that contains itself.", 0) end
+    seen[value] = true
+    local parts = {}
+    for k, v in pairs(value) do
+      parts[#parts + 1] = "[" .. serialize(k, seen) .. "] = " .. serialize(v, seen)
+    end
+    seen[value] = nil
+    return "{" .. table.concat(parts, ", ") .. "}"
+  end
+  error("Cannot save a " .. kind .. ".", 0)
+end
+
+local function deserialize(text)
+  return assert(load("return " .. text, "=state", "t", {}))()
+end
+
+return function(value) return serialize(value, {}) end, deserialize
+"#;
+
+fn setup_task_states(state: &mut lua::State) {
+    let status = state.load_buffer(STATE_SERIALIZATION.as_bytes(), "=state_serialization");
+    assert!(!status.is_err(), "STATE_SERIALIZATION does not compile.");
+    state.call(0, 2); // S: serialize deserialize
+    state.set_field(lua::REGISTRYINDEX, DESERIALIZE_STATE_KEY);
+    state.set_field(lua::REGISTRYINDEX, SERIALIZE_STATE_KEY);
+    state.new_table();
+    state.set_field(lua::REGISTRYINDEX, TASK_STATES_KEY);
+}
+
+/// Where the 'state' of the task 'name' in 'config_file' is saved. This is in the cache directory
+/// of the user, so that it survives reboots and is not shared with other users. Without one, it is
+/// next to the config file.
+fn state_file(config_file: &path::Path, name: &str) -> path::PathBuf {
+    let config_file = config_file
+        .canonicalize()
+        .unwrap_or_else(|_| config_file.to_path_buf());
+    let mut sha = sha1::Sha1::new();
+    sha.update(config_file.to_string_lossy().as_bytes());
+    sha.update(b"\n");
+    sha.update(name.as_bytes());
+    let file_name = format!("{}.state", sha.digest());
+    let cache_dir = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(path::PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| path::PathBuf::from(home).join(".cache")));
+    match cache_dir {
+        Some(cache_dir) => cache_dir.join("shell_grunt2").join(file_name),
+        None => config_file
+            .parent()
+            .unwrap_or_else(|| path::Path::new("."))
+            .join(format!(".shell_grunt2.{}", file_name)),
+    }
+}
+
+/// The 'state' table that is passed to all callbacks of a task. It lives as long as the config.
+/// With 'persist_state' it is also saved to disk once it is dropped, i.e. when the config is
+/// reloaded or we shut down. Saving after every callback would serialize the table for every
+/// event.
+struct TaskState {
+    lua_state: Rc<RefCell<lua::State>>,
+    key: i64,
+    // Where the table is saved, if it is.
+    file: Option<path::PathBuf>,
+    // What is in 'file', so that an unchanged table is not written again.
+    saved: Option<String>,
+    // The config file and a description of the task, for error messages.
+    config_file: path::PathBuf,
+    task: String,
+}
+
+impl TaskState {
+    /// Creates the table, with the saved contents if there are any.
+    fn new(
+        lua_state: Rc<RefCell<lua::State>>,
+        key: i64,
+        file: Option<path::PathBuf>,
+        config_file: path::PathBuf,
+        task: String,
+    ) -> (TaskState, Result<(), String>) {
+        let saved = file.as_ref().and_then(|file| fs::read_to_string(file).ok());
+        let mut state = lua_state.borrow_mut();
+        state.get_field(lua::REGISTRYINDEX, TASK_STATES_KEY); // S: <states>
+        let mut rv = Ok(());
+        match &saved {
+            Some(text) => {
+                state.get_field(lua::REGISTRYINDEX, DESERIALIZE_STATE_KEY);
+                state.push_string(text); // S: <states> deserialize text
+                if state.pcall(1, 1, 0).is_err() || !state.is_table(-1) {
+                    // S: <states> <error>
+                    let message = state.to_str_in_place(-1).unwrap_or_default().to_string();
+                    rv = Err(format!("Cannot read the saved state: {}", message));
+                    state.pop(1);
+                    state.new_table();
+                }
+            }
+            None => state.new_table(),
+        }
+        // S: <states> <state>
+        state.raw_seti(-2, key);
+        state.pop(1);
+        drop(state);
+        let task_state = TaskState {
+            lua_state,
+            key,
+            file,
+            // An empty table that was never saved does not need to be.
+            saved: Some(saved.unwrap_or_else(|| "{}".to_string())),
+            config_file,
+            task,
+        };
+        (task_state, rv)
+    }
+
+    fn push(&self, state: &mut lua::State) {
+        state.get_field(lua::REGISTRYINDEX, TASK_STATES_KEY); // S: <states>
+        state.raw_geti(-1, self.key); // S: <states> <state>
+        state.remove(-2);
+    }
+
+    /// Saves the table if it is persisted and changed.
+    fn save(&self, state: &mut lua::State) -> Result<(), String> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        state.get_field(lua::REGISTRYINDEX, SERIALIZE_STATE_KEY);
+        self.push(state); // S: serialize <state>
+        let is_err = state.pcall(1, 1, 0).is_err();
+        let text = state.to_str_in_place(-1).unwrap_or_default().to_string();
+        state.pop(1);
+        if is_err {
+            return Err(format!("Cannot save the state: {}", text));
+        }
+        if self.saved.as_ref() == Some(&text) {
+            return Ok(());
+        }
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|err| format!("Cannot create {}: {}", dir.display(), err))?;
+        }
+        fs::write(file, text)
+            .map_err(|err| format!("Cannot save the state to {}: {}", file.display(), err))
+    }
+}
+
+impl Drop for TaskState {
+    fn drop(&mut self) {
+        let lua_state = self.lua_state.clone();
+        let mut state = match lua_state.try_borrow_mut() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if let Err(message) = self.save(&mut state) {
+            let err = ConfigError::new(&self.config_file, message).with_task(self.task.clone());
+            println!("{}", err);
+        }
+    }
+}
+
 struct LuaTask {
     state: Rc<RefCell<lua::State>>,
     key: i64,
+    name: Option<String>,
+    // The config file the task comes from, for error messages.
+    file: path::PathBuf,
+    path_filter: PathFilter,
+    watch_roots: Vec<path::PathBuf>,
+    // Looked at for every event, so these are read once.
+    events: Vec<EventKind>,
+    respect_gitignore: bool,
+    on_change: OnChange,
+    // Relative paths in the task are relative to this directory.
+    config_dir: path::PathBuf,
+    budget: CallbackBudget,
+    // Set once a callback exceeded its budget, shared with the hooks.
+    misconfigured: Rc<Cell<bool>>,
+    task_state: Rc<TaskState>,
 }
 
 impl LuaTask {
-    fn new(state: Rc<RefCell<lua::State>>, key: i64) -> LuaTask {
-        LuaTask { state, key }
+    fn new(
+        state: Rc<RefCell<lua::State>>,
+        key: i64,
+        file: path::PathBuf,
+        config_dir: path::PathBuf,
+        budget: CallbackBudget,
+    ) -> Result<LuaTask, ConfigError> {
+        {
+            let mut state = state.borrow_mut();
+            state.push_integer(key); // S: D key
+            state.get_table(1); // S: D d
+            let is_table = state.is_table(-1);
+            state.pop(1);
+            if !is_table {
+                return Err(ConfigError::new(
+                    &file,
+                    format!("Expected task #{} to be a table.", key),
+                ));
+            }
+        }
+        // Replaced once the name is known.
+        let task_state = Rc::new(TaskState {
+            lua_state: state.clone(),
+            key,
+            file: None,
+            saved: None,
+            config_file: file.clone(),
+            task: String::new(),
+        });
+        let mut task = LuaTask {
+            state,
+            key,
+            name: None,
+            file,
+            path_filter: PathFilter::new(&config_dir, &[], &[]).unwrap(),
+            watch_roots: Vec::new(),
+            events: Vec::new(),
+            respect_gitignore: true,
+            on_change: OnChange::Restart,
+            config_dir: config_dir.clone(),
+            budget,
+            misconfigured: Rc::new(Cell::new(false)),
+            task_state,
+        };
+        task.name = task.get_string("name");
+        let state_file = match (&task.name, task.get_bool("persist_state")) {
+            (Some(name), Some(true)) => Some(state_file(&task.file, name)),
+            _ => None,
+        };
+        let (task_state, loaded) = TaskState::new(
+            task.state.clone(),
+            key,
+            state_file,
+            task.file.clone(),
+            task.describe(),
+        );
+        task.task_state = Rc::new(task_state);
+        if let Err(message) = loaded {
+            println!("{}", task.error(message));
+        }
+        task.path_filter = PathFilter::new(
+            &config_dir,
+            &task.get_string_list("include"),
+            &task.get_string_list("exclude"),
+        )
+        .map_err(|e| task.error(e))?;
+        task.watch_roots = config::watch_roots(&config_dir, &task.get_string_list("watch"))
+            .map_err(|e| task.error(e))?;
+        task.events = task
+            .get_string_list("events")
+            .iter()
+            .map(|name| name.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|e| task.error(e))?;
+        task.respect_gitignore = task.get_bool("respect_gitignore").unwrap_or(true);
+        if let Some(on_change) = task.get_string("on_change") {
+            task.on_change = on_change.parse().map_err(|e| task.error(e))?;
+        }
+        Ok(task)
+    }
+
+    /// Checks the values that are only read when they are needed, so that mistakes show up right
+    /// when the config is loaded.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(name) = self.get_string("stop_signal") {
+            if task::signal_from_name(&name).is_none() {
+                return Err(self.error(format!("Unknown stop_signal '{}'.", name)));
+            }
+        }
+        if self.get_bool("persist_state") == Some(true) && self.name.is_none() {
+            return Err(self.error("Expected a 'name' to persist the state."));
+        }
+        for hook in &[Hook::Start, Hook::Success, Hook::Failure, Hook::Finish] {
+            let mut state = self.state.borrow_mut();
+            self.get_value_in_our_dict(hook.name(), &mut state);
+            let is_valid = state.is_nil(-1) || state.is_fn(-1);
+            state.pop(2);
+            if !is_valid {
+                return Err(self.error(format!("Expected '{}' to be a function.", hook.name())));
+            }
+        }
+        // Commands computed by a function can only be checked when they are run.
+        if !self.is_fn("commands") {
+            self.commands(&[])?;
+        }
+        Ok(())
+    }
+
+    /// Finds keys that do not exist and values of the wrong type.
+    fn check_schema(&self) -> Vec<ConfigError> {
+        let mut problems = Vec::new();
+        let mut state = self.state.borrow_mut();
+        state.push_integer(self.key); // S: D key
+        state.get_table(1); // S: D d
+        state.push_nil(); // S: D d nil
+        while state.next(-2) {
+            // S: D d key value
+            let key = match state.type_of(-2) {
+                Some(lua::Type::String) => {
+                    state.to_str_in_place(-2).unwrap_or_default().to_string()
+                }
+                _ => {
+                    state.pop(1);
+                    problems.push(self.error("Expected only string keys."));
+                    continue;
+                }
+            };
+            match TASK_SCHEMA.iter().find(|(name, _)| *name == key) {
+                None => problems.push(self.error(format!("Unknown key '{}'.", key))),
+                Some((_, types)) => {
+                    if !types.iter().any(|t| t.matches(&mut state, -1)) {
+                        let names = types.iter().map(|t| t.name()).collect::<Vec<_>>();
+                        problems.push(self.error(format!(
+                            "Expected '{}' to be {}.",
+                            key,
+                            names.join(" or ")
+                        )));
+                    }
+                }
+            }
+            state.pop(1); // S: D d key
+        }
+        state.pop(1); // S: D
+        problems
+    }
+
+    /// Finds programs that are not on the PATH and work directories that do not exist.
+    fn check_commands(&self) -> Vec<ConfigError> {
+        // Commands computed by a function are only known when they are run.
+        if self.is_fn("commands") {
+            return Vec::new();
+        }
+        // Malformed commands are reported by 'validate'.
+        let commands = self.commands(&[]).unwrap_or_default();
+        config::check_commands(&commands)
+            .into_iter()
+            .map(|message| self.error(message))
+            .collect()
+    }
+
+    /// Describes this task for error messages.
+    fn describe(&self) -> String {
+        match &self.name {
+            Some(name) => format!("task #{} ({})", self.key, name),
+            None => format!("task #{}", self.key),
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> ConfigError {
+        ConfigError::new(&self.file, message).with_task(self.describe())
+    }
+
+    fn callback_error(
+        &self,
+        err: CallbackError,
+        callback: &str,
+        budget: CallbackBudget,
+    ) -> ConfigError {
+        callback_error(
+            err,
+            callback,
+            &self.file,
+            &self.describe(),
+            budget,
+            &self.misconfigured,
+        )
     }
 
     fn get_value_in_our_dict(&self, key: &str, state: &mut lua::State) {
@@ -67,6 +866,52 @@ impl LuaTask {
         state.get_table(-2); // S: D d <value>
     }
 
+    /// Reads the commands like they are in the config, calling 'commands' if it is a function.
+    fn read_commands(
+        &self,
+        changed_files: &[path::PathBuf],
+    ) -> Result<Vec<ShellCommand>, ConfigError> {
+        let mut state = self.state.borrow_mut();
+        self.get_value_in_our_dict("command", &mut state); // S: D d <command>
+        if !state.is_nil(-1) {
+            // Short form for a single command.
+            let rv = read_command(&mut state).map(|command| vec![command]);
+            state.pop(2); // S: D
+            return rv.map_err(|message| self.error(message));
+        }
+        state.pop(1); // S: D d
+        get_value_in_dict("commands", &mut state); // S: D d <commands>
+        if state.is_fn(-1) {
+            if self.misconfigured.get() {
+                state.pop(2); // S: D
+                return Err(
+                    self.error("The task is disabled because a callback exceeded its budget.")
+                );
+            }
+            push_path_list(changed_files, &mut state); // S: D d function changed_files
+            self.task_state.push(&mut state); // S: D d function changed_files <state>
+            let budget = self.budget.without_timeout();
+            let rv = call_with_budget(&mut state, budget, 2, 1);
+            if let Err(err) = rv {
+                // S: D d
+                state.pop(1); // S: D
+                return Err(self.callback_error(err, "commands", budget));
+            }
+            // S: D d <commands>
+        }
+        let rv = read_commands(&mut state);
+        state.pop(2); // S: D
+        rv.map_err(|message| self.error(message))
+    }
+
+    fn is_fn(&self, key: &str) -> bool {
+        let mut state = self.state.borrow_mut();
+        self.get_value_in_our_dict(key, &mut state); // S: D d <value>
+        let rv = state.is_fn(-1);
+        state.pop(2);
+        rv
+    }
+
     fn get_string(&self, key: &str) -> Option<String> {
         let mut state = self.state.borrow_mut();
         self.get_value_in_our_dict(key, &mut state); // S: D d <value>
@@ -90,10 +935,30 @@ impl LuaTask {
         state.pop(1);
         rv
     }
+
+    fn get_string_list(&self, key: &str) -> Vec<String> {
+        let mut state = self.state.borrow_mut();
+        self.get_value_in_our_dict(key, &mut state); // S: D d <value>
+        let rv = pop_string_list(&mut state);
+        state.pop(1);
+        rv
+    }
 }
 
 impl Task for LuaTask {
-    fn should_run(&self, path: &path::Path) -> bool {
+    fn should_run(&self, event: &FileEvent) -> bool {
+        if !self.events.is_empty() && !self.events.contains(&event.kind) {
+            return false;
+        }
+
+        // Globs are cheap, so they go first and 'should_run' only sees what passed them.
+        if !self.path_filter.matches(&event.path) {
+            return false;
+        }
+
+        if self.misconfigured.get() {
+            return false;
+        }
         let mut state = self.state.borrow_mut();
         self.get_value_in_our_dict("should_run", &mut state);
         if state.is_nil(-1) {
@@ -101,10 +966,23 @@ impl Task for LuaTask {
             state.pop(2);
             return true;
         }
-        state.push_string(&path.to_string_lossy()); // S: D d function path
-        state.call(1, 1); // S: D d <result>
-        let rv = state.to_bool(-1);
-        state.pop(2);
+        state.push_string(&event.path.to_string_lossy()); // S: D d function path
+        push_event(event, &mut state); // S: D d function path event
+        self.task_state.push(&mut state); // S: D d function path event <state>
+        let rv = match call_with_budget(&mut state, self.budget, 3, 1) {
+            // S: D d <result>
+            Ok(()) => {
+                let rv = state.to_bool(-1);
+                state.pop(1);
+                rv
+            }
+            // S: D d
+            Err(err) => {
+                println!("{}", self.callback_error(err, "should_run", self.budget));
+                false
+            }
+        };
+        state.pop(1); // S: D
         rv
     }
 
@@ -113,44 +991,56 @@ impl Task for LuaTask {
             .map(time::Duration::milliseconds)
             .unwrap_or_else(|| time::Duration::milliseconds(50))
     }
+
+    fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    fn depends_on(&self) -> Vec<String> {
+        self.get_string_list("depends_on")
+    }
+
+    fn respect_gitignore(&self) -> bool {
+        self.respect_gitignore
+    }
+
+    fn exclusive_group(&self) -> Option<String> {
+        self.get_string("exclusive_group")
+    }
+
+    fn on_change(&self) -> OnChange {
+        self.on_change
+    }
+
+    fn tags(&self) -> Vec<String> {
+        self.get_string_list("tags")
+    }
+
+    fn watch_roots(&self) -> &[path::PathBuf] {
+        &self.watch_roots
+    }
 }
 
 impl ShellTask for LuaTask {
-    // TODO(sirver): If there is only one command in a chain, a short form should be acceptable in
-    // the Lua file.
-    fn commands(&self) -> Vec<ShellCommand> {
-        let mut state = self.state.borrow_mut();
-        self.get_value_in_our_dict("commands", &mut state);
-        if state.is_nil(-1) {
-            panic!("Expected commands, but was not found.");
-        }
-        let mut result = Vec::new();
-        state.push_nil(); // S: D "commands dict" nil
-        while state.next(-2) {
-            // S: D "commands dict" key value
-            get_value_in_dict("name", &mut state);
-            let name = pop_string(&mut state).expect("name in commands.");
-            get_value_in_dict("command", &mut state);
-            let command = pop_string(&mut state).expect("command in commands.");
-            get_value_in_dict("work_directory", &mut state);
-            let work_directory = pop_string(&mut state).map(path::PathBuf::from);
-            state.pop(1); // S: D "commands dict" key
-            result.push(ShellCommand {
-                name,
-                command,
-                work_directory,
-            });
+    fn commands(&self, changed_files: &[path::PathBuf]) -> Result<Vec<ShellCommand>, ConfigError> {
+        let mut commands = self.read_commands(changed_files)?;
+        for command in &mut commands {
+            command.work_directory = command
+                .work_directory
+                .take()
+                .map(|dir| self.config_dir.join(dir));
         }
-        state.pop(1); // S: D
-        result
+        Ok(commands)
     }
 
     fn redirect_stdout(&self) -> Option<path::PathBuf> {
-        self.get_string("redirect_stdout").map(path::PathBuf::from)
+        self.get_string("redirect_stdout")
+            .map(|file| self.config_dir.join(file))
     }
 
     fn redirect_stderr(&self) -> Option<path::PathBuf> {
-        self.get_string("redirect_stderr").map(path::PathBuf::from)
+        self.get_string("redirect_stderr")
+            .map(|file| self.config_dir.join(file))
     }
 
     fn supress_stderr(&self) -> bool {
@@ -161,111 +1051,337 @@ impl ShellTask for LuaTask {
         self.get_bool("suppress_stdout").unwrap_or(false)
     }
 
+    fn stop_signal(&self) -> libc::c_int {
+        self.get_string("stop_signal")
+            .and_then(|name| task::signal_from_name(&name))
+            .unwrap_or(libc::SIGTERM)
+    }
+
+    fn stop_timeout(&self) -> time::Duration {
+        self.get_int("stop_timeout")
+            .map(time::Duration::milliseconds)
+            .unwrap_or_else(|| time::Duration::milliseconds(2000))
+    }
+
+    fn hooks(&self) -> Option<Box<dyn RunHooks>> {
+        Some(Box::new(LuaHooks {
+            state: self.state.clone(),
+            key: self.key,
+            file: self.file.clone(),
+            task: self.describe(),
+            budget: self.budget,
+            misconfigured: self.misconfigured.clone(),
+            task_state: self.task_state.clone(),
+        }))
+    }
+
     fn environment(&self) -> Option<HashMap<String, String>> {
         let mut state = self.state.borrow_mut();
         self.get_value_in_our_dict("environment", &mut state);
-        if state.is_nil(-1) {
-            state.pop(1);
+        if !state.is_table(-1) {
+            state.pop(2);
             return None;
         }
         let mut result = HashMap::new();
-        state.push_nil(); // S: D "env dict" nil
+        state.push_nil(); // S: D d "env dict" nil
         while state.next(-2) {
-            // S: D "env dict" key value
-            let value = pop_string(&mut state).unwrap();
-            let key = state.check_string(-1).to_string();
-            result.insert(key, value);
+            // S: D d "env dict" key value
+            let value = pop_string(&mut state);
+            if let (Some(value), true) = (value, state.is_string(-1)) {
+                let key = state.check_string(-1).to_string();
+                result.insert(key, value);
+            }
         }
-        state.pop(1); // S: D
+        state.pop(2); // S: D
         Some(result)
     }
 }
 
-#[allow(non_snake_case)]
-unsafe extern "C" fn basename(L: *mut lua_State) -> libc::c_int {
-    let mut state = lua::State::from_ptr(L);
-    let string: String = state.check_string(-1).to_string();
-    let p = path::Path::new(&string);
-    match p.file_name() {
-        Some(s) => {
-            state.push_string(&s.to_string_lossy());
-            1
-        }
-        None => 0,
-    }
+/// Calls the hook functions of a task, which outlive the 'LuaTask' they come from.
+struct LuaHooks {
+    state: Rc<RefCell<lua::State>>,
+    key: i64,
+    file: path::PathBuf,
+    // Describes the task for error messages.
+    task: String,
+    budget: CallbackBudget,
+    misconfigured: Rc<Cell<bool>>,
+    task_state: Rc<TaskState>,
 }
 
-#[allow(non_snake_case)]
-unsafe extern "C" fn dirname(L: *mut lua_State) -> libc::c_int {
-    let mut state = lua::State::from_ptr(L);
-    let string: String = state.check_string(-1).to_string();
-    let p = path::Path::new(&string);
-    match p.parent() {
-        Some(s) => {
-            state.push_string(&s.to_string_lossy());
-            1
+impl RunHooks for LuaHooks {
+    fn call(&self, hook: Hook, result: &RunResult) {
+        if self.misconfigured.get() {
+            return;
         }
-        None => 0,
-    }
-}
-
-#[allow(non_snake_case)]
-unsafe extern "C" fn ext(L: *mut lua_State) -> libc::c_int {
-    let mut state = lua::State::from_ptr(L);
-    let string: String = state.check_string(-1).to_string();
-    let p = path::Path::new(&string);
-    match p.extension() {
-        Some(ext) => {
-            state.push_string(&ext.to_string_lossy());
-            1
+        let mut state = self.state.borrow_mut();
+        // S: D
+        state.push_integer(self.key); // S: D key
+        state.get_table(1); // S: D d
+        state.get_field(-1, hook.name()); // S: D d <hook>
+        if !state.is_fn(-1) {
+            state.pop(2);
+            return;
+        }
+        push_run_result(result, &mut state); // S: D d <hook> <result>
+        self.task_state.push(&mut state); // S: D d <hook> <result> <state>
+        let budget = self.budget.without_timeout();
+        if let Err(err) = call_with_budget(&mut state, budget, 2, 0) {
+            let err = callback_error(
+                err,
+                hook.name(),
+                &self.file,
+                &self.task,
+                budget,
+                &self.misconfigured,
+            );
+            println!("Error in {}: {}", hook.name(), err);
         }
-        None => 0,
+        state.pop(1); // S: D
     }
 }
 
-fn inject_path_functions(state: &mut lua::State) {
-    state.get_global("string"); // S: <string>
+// Key in the Lua registry of the list of files loaded by the config.
+const LOADED_FILES_KEY: &str = "shell_grunt2.loaded_files";
 
-    state.push_string("ext");
-    state.push_fn(Some(ext));
-    state.set_table(-3);
+// Called with a list and the config directory. Makes 'require' find modules next to the config
+// file and appends every file loaded through 'require', 'dofile' or 'loadfile' to the list.
+const TRACK_LOADED_FILES: &str = r#"
+local loaded_files, config_dir = ...
+package.path = config_dir .. "/?.lua;" .. config_dir .. "/?/init.lua;" .. package.path
 
-    state.push_string("basename");
-    state.push_fn(Some(basename));
-    state.set_table(-3);
+local function record(path)
+  if path ~= nil then
+    loaded_files[#loaded_files + 1] = path
+  end
+end
 
-    state.push_string("dirname");
-    state.push_fn(Some(dirname));
-    state.set_table(-3);
+local original_dofile, original_loadfile = dofile, loadfile
+dofile = function(path)
+  record(path)
+  return original_dofile(path)
+end
+loadfile = function(path, ...)
+  record(path)
+  return original_loadfile(path, ...)
+end
 
-    state.pop(1);
+-- Tail calls keep the wrappers out of the positions in error messages.
+local search_lua_file = package.searchers[2]
+package.searchers[2] = function(name)
+  record(package.searchpath(name, package.path))
+  return search_lua_file(name)
+end
+"#;
+
+fn track_loaded_files(state: &mut lua::State, config_dir: &path::Path) {
+    let status = state.load_buffer(TRACK_LOADED_FILES.as_bytes(), "=track_loaded_files");
+    assert!(!status.is_err(), "TRACK_LOADED_FILES does not compile.");
+    // S: function
+    state.new_table(); // S: function <files>
+    state.push_value(-1); // S: function <files> <files>
+    state.set_field(lua::REGISTRYINDEX, LOADED_FILES_KEY); // S: function <files>
+    state.push_string(&config_dir.to_string_lossy()); // S: function <files> config_dir
+    state.call(2, 0);
+}
+
+/// Returns the canonical paths of the files recorded by 'track_loaded_files'.
+fn loaded_files(state: &mut lua::State) -> Vec<path::PathBuf> {
+    state.get_field(lua::REGISTRYINDEX, LOADED_FILES_KEY);
+    pop_string_list(state)
+        .iter()
+        .filter_map(|path| path::Path::new(path).canonicalize().ok())
+        .collect()
+}
+
+/// A config file that ran without errors.
+struct LoadedFile {
+    state: Rc<RefCell<lua::State>>,
+    // Keys of the entries of the returned table that are tasks.
+    task_keys: Vec<i64>,
+    // Keys of all other entries.
+    setting_keys: Vec<String>,
+    max_parallel: Option<usize>,
+    budget: CallbackBudget,
+    profiles: HashMap<String, Vec<String>>,
+    configs: Vec<path::PathBuf>,
+    config_dir: path::PathBuf,
+    // The config file and all files it loaded.
+    files: Vec<path::PathBuf>,
 }
 
-pub fn run_file(path: &path::Path) -> Vec<Box<dyn Task>> {
+fn load_file(path: &path::Path) -> Result<LoadedFile, ConfigError> {
     let mut state = lua::State::new();
     state.open_libs();
 
+    let config_dir = path
+        .canonicalize()
+        .ok()
+        .and_then(|path| path.parent().map(path::Path::to_path_buf))
+        .unwrap_or_default();
+    lua_grunt::inject(&mut state, &config_dir);
+    lua_path::inject(&mut state);
+    track_loaded_files(&mut state, &config_dir);
+    setup_task_states(&mut state);
+    setup_budget_hook(&mut state);
+
     let path_utf8 = path.to_string_lossy();
     match state.do_file(&path_utf8) {
         lua::ThreadStatus::Ok => (),
         _ => {
-            let error_message = pop_string(&mut state).unwrap();
-            panic!("{}", error_message);
+            let error_message = pop_string(&mut state).unwrap_or_default();
+            return Err(lua_error(path, &error_message));
         }
     };
+    if !state.is_table(1) {
+        return Err(ConfigError::new(
+            path,
+            "Expected a table of tasks to be returned.",
+        ));
+    }
 
-    inject_path_functions(&mut state);
+    let mut files = vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())];
+    for file in loaded_files(&mut state) {
+        if !files.contains(&file) {
+            files.push(file);
+        }
+    }
 
-    let mut tasks = Vec::new();
-    let state_rc = Rc::new(RefCell::new(state));
-    let mut state = state_rc.borrow_mut();
+    get_value_in_dict("max_parallel", &mut state);
+    let max_parallel = pop_int(&mut state)
+        .filter(|max_parallel| *max_parallel > 0)
+        .map(|max_parallel| max_parallel as usize);
+
+    let mut budget = CallbackBudget::default();
+    get_value_in_dict("callback_timeout", &mut state);
+    if let Some(timeout) = pop_int(&mut state).filter(|timeout| *timeout > 0) {
+        budget.timeout = Some(Duration::from_millis(timeout as u64));
+    }
+    get_value_in_dict("callback_instructions", &mut state);
+    if let Some(instructions) = pop_int(&mut state).filter(|instructions| *instructions > 0) {
+        budget.instructions = instructions;
+    }
+
+    get_value_in_dict("profiles", &mut state); // S: D <profiles>
+    let profiles = read_profiles(&mut state);
+    state.pop(1); // S: D
+    let profiles = profiles.map_err(|message| ConfigError::new(path, message))?;
+
+    get_value_in_dict("configs", &mut state);
+    let configs = config::included_configs(&config_dir, &pop_string_list(&mut state));
 
+    let mut task_keys = Vec::new();
+    let mut setting_keys = Vec::new();
     state.push_nil(); // S: D nil
     while state.next(-2) {
-        let key = state.check_integer(-2); // S: D key value
+        // S: D key value
         state.pop(1); // S: D key
-        tasks.push(Box::new(LuaTask::new(state_rc.clone(), key)) as Box<dyn Task>);
+        if state.is_integer(-1) {
+            task_keys.push(state.check_integer(-1));
+        } else if state.type_of(-1) == Some(lua::Type::String) {
+            // Global settings like 'max_parallel'.
+            setting_keys.push(state.to_str_in_place(-1).unwrap_or_default().to_string());
+        }
     }
     // S: D
-    tasks
+
+    Ok(LoadedFile {
+        state: Rc::new(RefCell::new(state)),
+        task_keys,
+        setting_keys,
+        max_parallel,
+        budget,
+        profiles,
+        configs,
+        config_dir,
+        files,
+    })
+}
+
+pub fn run_file(path: &path::Path) -> Result<Config, ConfigError> {
+    let loaded = load_file(path)?;
+    let mut tasks = Vec::new();
+    for key in loaded.task_keys {
+        let task = LuaTask::new(
+            loaded.state.clone(),
+            key,
+            path.to_path_buf(),
+            loaded.config_dir.clone(),
+            loaded.budget,
+        )?;
+        task.validate()?;
+        tasks.push(Box::new(task) as Box<dyn Task>);
+    }
+    dispatch::dependencies(&tasks).map_err(|message| ConfigError::new(path, message))?;
+    Ok(Config {
+        tasks,
+        max_parallel: loaded.max_parallel,
+        profiles: loaded.profiles,
+        configs: loaded.configs,
+        files: loaded.files,
+    })
+}
+
+/// Loads the config file at 'path' like 'run_file', but returns all problems instead of stopping
+/// at the first. This is stricter than 'run_file': unknown keys, values of the wrong type,
+/// programs that are not on the PATH and missing work directories are problems too.
+pub fn check_file(path: &path::Path) -> CheckedFile {
+    let loaded = match load_file(path) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            return CheckedFile {
+                problems: vec![err],
+                configs: Vec::new(),
+            }
+        }
+    };
+
+    let mut problems = Vec::new();
+    for key in loaded.setting_keys {
+        if !SETTINGS.contains(&key.as_str()) {
+            problems.push(ConfigError::new(
+                path,
+                format!("Unknown setting '{}'.", key),
+            ));
+        }
+    }
+
+    let mut tasks = Vec::new();
+    let mut all_loaded = true;
+    for key in loaded.task_keys {
+        let task = match LuaTask::new(
+            loaded.state.clone(),
+            key,
+            path.to_path_buf(),
+            loaded.config_dir.clone(),
+            loaded.budget,
+        ) {
+            Ok(task) => task,
+            Err(err) => {
+                problems.push(err);
+                all_loaded = false;
+                continue;
+            }
+        };
+        problems.extend(task.check_schema());
+        if let Err(err) = task.validate() {
+            // Values of the wrong type might already be reported by the schema.
+            let message = err.to_string();
+            if !problems.iter().any(|p| p.to_string() == message) {
+                problems.push(err);
+            }
+        }
+        problems.extend(task.check_commands());
+        tasks.push(Box::new(task) as Box<dyn Task>);
+    }
+    // Tasks that did not load would show up as unknown dependencies.
+    if all_loaded {
+        if let Err(message) = dispatch::dependencies(&tasks) {
+            problems.push(ConfigError::new(path, message));
+        }
+    }
+    CheckedFile {
+        problems,
+        configs: loaded.configs,
+    }
 }
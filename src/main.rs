@@ -1,26 +1,27 @@
-use clap;
-use ctrlc;
-use notify;
-use shell_grunt2;
-use time;
-
 use notify::Watcher;
+use shell_grunt2::config;
+use shell_grunt2::dispatch;
+use shell_grunt2::ignore_filter::IgnoreFilter;
 use shell_grunt2::lockfile;
-use shell_grunt2::task::{Runnable, RunningTask, Task};
+use shell_grunt2::task::{self, FileEvent, RunContext, Runnable, RunningTask, Task};
+use shell_grunt2::{Event, ShellGrunt2};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
-fn update() -> Result<(), Box<dyn (::std::error::Error)>> {
+fn update() -> Result<(), Box<dyn ::std::error::Error>> {
     let target = self_update::get_target();
     self_update::backends::github::Update::configure()
         .repo_owner("SirVer")
         .repo_name("shell_grunt2")
-        .target(&target)
+        .target(target)
         .bin_name("shell_grunt2")
         .show_download_progress(true)
         .show_output(false)
@@ -31,8 +32,14 @@ fn update() -> Result<(), Box<dyn (::std::error::Error)>> {
     Ok(())
 }
 
+// Clones share their state, one is handed to the dispatcher with the tasks of the file.
+#[derive(Clone)]
 struct ReloadWatcherFile {
-    file_name: PathBuf,
+    // The watcher file and all files it loaded.
+    file_names: Rc<RefCell<Vec<PathBuf>>>,
+    // The files can be anywhere and 'file_names' grows after the task was handed out, so the
+    // whole file system is watched. Only changes to the files themselves trigger the task.
+    watch_roots: Vec<PathBuf>,
     should_reload: Arc<AtomicBool>,
 }
 
@@ -42,73 +49,511 @@ impl RunningTask for RunningReloadWatcherFile {
     fn done(&mut self) -> bool {
         true
     }
-    fn wait(self: Box<Self>) {}
+    fn wait(self: Box<Self>) -> i32 {
+        0
+    }
     fn interrupt(self: Box<Self>) {}
 }
 
 impl Runnable for ReloadWatcherFile {
-    fn run(&self) -> Box<dyn RunningTask> {
+    fn run(&self, _: &RunContext) -> Box<dyn RunningTask> {
         self.should_reload.store(true, Ordering::SeqCst);
         Box::new(RunningReloadWatcherFile {})
     }
 }
 
 impl Task for ReloadWatcherFile {
-    fn should_run(&self, path: &Path) -> bool {
-        path == self.file_name
+    fn should_run(&self, event: &FileEvent) -> bool {
+        self.file_names.borrow().contains(&event.path)
     }
 
     fn start_delay(&self) -> time::Duration {
         time::Duration::milliseconds(0)
     }
+
+    fn respect_gitignore(&self) -> bool {
+        // The watcher file might well be ignored, but we always want to know about changes.
+        false
+    }
+
+    fn watch_roots(&self) -> &[PathBuf] {
+        &self.watch_roots
+    }
 }
 
-fn watch_file_events(watcher_file: impl AsRef<Path>) {
+/// Sends 'Event::Interrupt' on the first Ctrl-C and kills all running commands on the second.
+/// The returned flag is set once Ctrl-C was pressed.
+fn handle_interrupts(events_tx: mpsc::Sender<Event>) -> Arc<AtomicBool> {
     let saw_interrupt_signal = Arc::new(AtomicBool::new(false));
     let r = saw_interrupt_signal.clone();
     ctrlc::set_handler(move || {
-        r.store(true, Ordering::SeqCst);
+        if r.swap(true, Ordering::SeqCst) {
+            // Second Ctrl-C, the user does not want to wait for the commands to stop.
+            task::kill_all_running();
+            return;
+        }
+        let _ = events_tx.send(Event::Interrupt);
     })
     .expect("Error setting Ctrl-C handler");
+    saw_interrupt_signal
+}
+
+/// Calls 'visit' for 'files' and all config files listed in their 'configs', each file once.
+/// 'visit' returns its result for the file and the configs listed in it.
+fn visit_all<T>(
+    files: &[PathBuf],
+    mut visit: impl FnMut(&Path) -> (T, Vec<PathBuf>),
+) -> Vec<(PathBuf, T)> {
+    let mut result = Vec::new();
+    let mut to_visit = files.to_vec();
+    let mut seen = Vec::new();
+    while !to_visit.is_empty() {
+        let file = to_visit.remove(0);
+        let canonical = file.canonicalize().unwrap_or_else(|_| file.clone());
+        if seen.contains(&canonical) {
+            continue;
+        }
+        seen.push(canonical);
+        let (value, configs) = visit(&file);
+        to_visit.extend(configs);
+        result.push((file, value));
+    }
+    result
+}
+
+/// Loads 'files' and all config files listed in their 'configs', each file once.
+fn load_all(files: &[PathBuf]) -> Vec<(PathBuf, Result<config::Config, config::ConfigError>)> {
+    visit_all(files, |file| {
+        let config = config::load(file);
+        let configs = match &config {
+            Ok(config) => config.configs.clone(),
+            Err(_) => Vec::new(),
+        };
+        (config, configs)
+    })
+}
+
+/// Runs the tasks called 'names' one after the other, just like watch mode would run them, and
+/// stops at the first one that fails. Tasks run in the given order, except that the ones others
+/// depend on go first. Returns the exit code for the process, which is the one of the failed task.
+fn run_tasks(watcher_files: &[PathBuf], names: &[&str]) -> i32 {
+    let mut configs = Vec::new();
+    let mut dependencies = Vec::new();
+    for (file, config) in load_all(watcher_files) {
+        let config = config.and_then(|config| {
+            let deps = dispatch::dependencies(&config.tasks)
+                .map_err(|message| config::ConfigError::new(&file, message))?;
+            Ok((config, deps))
+        });
+        match config {
+            Ok((config, deps)) => {
+                configs.push(config);
+                dependencies.push(deps);
+            }
+            Err(err) => {
+                println!("{}", err);
+                return 1;
+            }
+        }
+    }
+    // Index of the config and of the task in it for each name.
+    let mut tasks = Vec::new();
+    for name in names {
+        let found = configs.iter().enumerate().find_map(|(config_idx, config)| {
+            let task_idx = config
+                .tasks
+                .iter()
+                .position(|task| task.name().as_deref() == Some(*name))?;
+            Some((config_idx, task_idx))
+        });
+        match found {
+            Some(found) => tasks.push((*name, found)),
+            None => {
+                let files = watcher_files
+                    .iter()
+                    .map(|file| file.display().to_string())
+                    .collect::<Vec<_>>();
+                println!("There is no task named '{}' in {}.", name, files.join(", "));
+                return 1;
+            }
+        }
+    }
+
+    let mut ordered = Vec::new();
+    while !tasks.is_empty() {
+        // There are no cycles, so one of the tasks does not depend on any of the others.
+        let next = tasks
+            .iter()
+            .position(|(_, (config_idx, task_idx))| {
+                !tasks.iter().any(|(_, (other_config, other_task))| {
+                    other_config == config_idx
+                        && dependencies[*config_idx][*task_idx].contains(other_task)
+                })
+            })
+            .unwrap();
+        ordered.push(tasks.remove(next));
+    }
+
+    let (events_tx, events_rx) = mpsc::channel();
+    handle_interrupts(events_tx.clone());
+    for (run_id, (name, (config_idx, task_idx))) in ordered.into_iter().enumerate() {
+        let task = &configs[config_idx].tasks[task_idx];
+        let context = RunContext {
+            task_name: name.to_string(),
+            run_id: run_id as u64 + 1,
+            changed_files: Vec::new(),
+            events_tx: events_tx.clone(),
+        };
+        let mut running_task = task.run(&context);
+        while !running_task.done() {
+            if let Ok(Event::Interrupt) = events_rx.recv() {
+                running_task.interrupt();
+                return 128 + libc::SIGINT;
+            }
+        }
+        let exit_code = running_task.wait();
+        if exit_code != 0 {
+            return exit_code;
+        }
+    }
+    0
+}
+
+/// Prints all problems in 'watcher_files' and the config files they list. Returns the exit code
+/// for the process.
+fn check(watcher_files: &[PathBuf]) -> i32 {
+    let mut exit_code = 0;
+    // Checking runs each file once, which also finds the configs listed in it.
+    let checked = visit_all(watcher_files, |file| {
+        let checked = config::check(file);
+        (checked.problems, checked.configs)
+    });
+    for (watcher_file, problems) in checked {
+        if problems.is_empty() {
+            println!("No problems found in {}.", watcher_file.display());
+            continue;
+        }
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        println!(
+            "Found {} problem(s) in {}.",
+            problems.len(),
+            watcher_file.display()
+        );
+        exit_code = 1;
+    }
+    exit_code
+}
+
+/// A config file given with '-f' or listed in 'configs' of another one. It is reloaded on its own
+/// when it or any file it loaded changes.
+struct WatchedConfig {
+    // Canonical path of the config file.
+    file: PathBuf,
+    // True once its tasks were handed to the dispatcher.
+    has_tasks: bool,
+    // Of the tasks from the last time the file loaded without errors, which keep running until it
+    // is fixed.
+    watch_roots: Vec<PathBuf>,
+    max_parallel: Option<usize>,
+    configs: Vec<PathBuf>,
+    // From the last time the file loaded, '--profile' can name a profile from any config.
+    profiles: HashMap<String, Vec<String>>,
+    // Loaded, but its tasks are not selected and handed to the dispatcher yet.
+    loaded: Option<config::Config>,
+    // Knows the config file and all files it loaded.
+    reload_task: ReloadWatcherFile,
+}
+
+impl WatchedConfig {
+    fn new(file: PathBuf) -> Self {
+        WatchedConfig {
+            reload_task: ReloadWatcherFile {
+                file_names: Rc::new(RefCell::new(vec![file.clone()])),
+                watch_roots: file
+                    .ancestors()
+                    .last()
+                    .map(Path::to_path_buf)
+                    .into_iter()
+                    .collect(),
+                // Not loaded yet.
+                should_reload: Arc::new(AtomicBool::new(true)),
+            },
+            file,
+            has_tasks: false,
+            watch_roots: Vec::new(),
+            max_parallel: None,
+            configs: Vec::new(),
+            profiles: HashMap::new(),
+            loaded: None,
+        }
+    }
+
+    fn should_reload(&self) -> bool {
+        self.reload_task.should_reload.load(Ordering::SeqCst)
+    }
+
+    /// Loads the config file. Its tasks are handed out by 'set_tasks', once the profiles of all
+    /// configs are known.
+    fn reload(&mut self, current_dir: &Path, shell_grunt2: &mut ShellGrunt2) {
+        self.reload_task
+            .should_reload
+            .store(false, Ordering::SeqCst);
+        match config::load(&self.file) {
+            Ok(config) => {
+                *self.reload_task.file_names.borrow_mut() = config.files.clone();
+                self.configs = config.configs.clone();
+                self.profiles = config.profiles.clone();
+                self.loaded = Some(config);
+            }
+            Err(err) => self.keep_previous_tasks(err, current_dir, shell_grunt2),
+        }
+    }
+
+    /// Selects the tasks of the loaded config file with 'profiles' from all configs and replaces
+    /// its tasks in 'shell_grunt2'.
+    fn set_tasks(
+        &mut self,
+        tag_filter: &config::TagFilter,
+        profiles: &HashMap<String, Vec<String>>,
+        current_dir: &Path,
+        shell_grunt2: &mut ShellGrunt2,
+    ) {
+        let mut config = match self.loaded.take() {
+            Some(config) => config,
+            None => return,
+        };
+        let result = config.select_tasks(tag_filter, profiles).and_then(|()| {
+            let watch_roots = config
+                .tasks
+                .iter()
+                .flat_map(|task| task.watch_roots().iter().cloned())
+                .collect();
+            let mut tasks: Vec<Box<dyn Task>> = vec![Box::new(self.reload_task.clone())];
+            tasks.extend(config.tasks);
+            shell_grunt2.set_tasks(&self.file, tasks)?;
+            self.has_tasks = true;
+            self.watch_roots = watch_roots;
+            self.max_parallel = config.max_parallel;
+            Ok(())
+        });
+        match result {
+            Ok(()) => println!(
+                "Watching file system with tasks from {}",
+                self.display_path(current_dir).display()
+            ),
+            Err(err) => self.keep_previous_tasks(err, current_dir, shell_grunt2),
+        }
+    }
+
+    fn display_path(&self, current_dir: &Path) -> PathBuf {
+        pathdiff::diff_paths(&self.file, current_dir).unwrap_or_else(|| self.file.clone())
+    }
+
+    fn keep_previous_tasks(
+        &mut self,
+        err: config::ConfigError,
+        current_dir: &Path,
+        shell_grunt2: &mut ShellGrunt2,
+    ) {
+        println!("{}", err);
+        println!(
+            "Keeping the previous tasks until {} is fixed.",
+            self.display_path(current_dir).display()
+        );
+        // The error might be in a file that was not loaded before.
+        if let Ok(file) = err.file.canonicalize() {
+            let mut file_names = self.reload_task.file_names.borrow_mut();
+            if !file_names.contains(&file) {
+                file_names.push(file);
+            }
+        }
+        // Without its reload task, we would not notice when the file is fixed.
+        if !self.has_tasks {
+            let reload_task: Box<dyn Task> = Box::new(self.reload_task.clone());
+            match shell_grunt2.set_tasks(&self.file, vec![reload_task]) {
+                Ok(()) => self.has_tasks = true,
+                Err(err) => println!("{}", err),
+            }
+        }
+    }
+}
+
+/// Runs until the user interrupts us. The 'watcher_files' are canonical.
+fn watch_file_events(
+    watcher_files: &[PathBuf],
+    jobs: Option<usize>,
+    no_ignore: bool,
+    tag_filter: &config::TagFilter,
+    watch_dirs: &[PathBuf],
+) {
+    // All events reach the dispatcher through this channel, so that it can sleep until there is
+    // something to do.
+    let (events_tx, events_rx) = mpsc::channel();
+
+    let saw_interrupt_signal = handle_interrupts(events_tx.clone());
 
     let current_dir = std::fs::canonicalize(".").unwrap();
-    let watcher_file = std::fs::canonicalize(watcher_file.as_ref()).unwrap();
+    let mut default_roots = vec![current_dir.clone()];
+    default_roots.extend(watch_dirs.iter().cloned());
+    let ignore_filter = if no_ignore {
+        None
+    } else {
+        Some(IgnoreFilter::new())
+    };
+    let mut shell_grunt2 = ShellGrunt2::new(
+        &events_rx,
+        events_tx.clone(),
+        ignore_filter,
+        default_roots.clone(),
+    );
+
+    let root_files = watcher_files.to_vec();
+    let mut configs = root_files
+        .iter()
+        .cloned()
+        .map(WatchedConfig::new)
+        .collect::<Vec<_>>();
+    // The tags of '--profile' the tasks were selected with, None if no config defined it.
+    let mut profile_tags = None;
     loop {
-        let diff = pathdiff::diff_paths(&watcher_file, &current_dir);
-        let disp = match &diff {
-            Some(diff) => diff,
-            None => &watcher_file,
+        // Only the configs that changed are reloaded, the tasks of the others keep running. Configs
+        // can list new configs when they are reloaded, which are loaded right away.
+        let mut reloaded = Vec::new();
+        let mut idx = 0;
+        while idx < configs.len() {
+            if configs[idx].should_reload() {
+                configs[idx].reload(&current_dir, &mut shell_grunt2);
+                reloaded.push(configs[idx].file.clone());
+            }
+            for file in configs[idx].configs.clone() {
+                if !configs.iter().any(|config| config.file == file) {
+                    configs.push(WatchedConfig::new(file));
+                }
+            }
+            idx += 1;
+        }
+        // Configs that are no longer listed anywhere are dropped.
+        let mut listed = root_files.clone();
+        let mut idx = 0;
+        while idx < listed.len() {
+            if let Some(config) = configs.iter().find(|config| config.file == listed[idx]) {
+                for file in &config.configs {
+                    if !listed.contains(file) {
+                        listed.push(file.clone());
+                    }
+                }
+            }
+            idx += 1;
+        }
+        for config in &configs {
+            if !listed.contains(&config.file) {
+                shell_grunt2.remove_tasks(&config.file);
+            }
+        }
+        configs.retain(|config| listed.contains(&config.file));
+
+        // A profile can be defined in any config and selects the tasks of all of them. If it
+        // changed, the tasks of the other configs are selected again.
+        let merged_profiles = |configs: &[WatchedConfig]| {
+            let mut profiles = HashMap::new();
+            for config in configs {
+                for (name, tags) in &config.profiles {
+                    profiles
+                        .entry(name.clone())
+                        .or_insert_with(Vec::new)
+                        .extend(tags.iter().cloned());
+                }
+            }
+            profiles
         };
-        println!("Watching file system with tasks from {}", disp.display());
+        let mut profiles = merged_profiles(&configs);
+        if let Some(profile) = &tag_filter.profile {
+            let tags = profiles.get(profile).cloned();
+            if tags != profile_tags {
+                for config in &mut configs {
+                    if !reloaded.contains(&config.file) {
+                        config.reload(&current_dir, &mut shell_grunt2);
+                    }
+                }
+                profiles = merged_profiles(&configs);
+                profile_tags = profiles.get(profile).cloned();
+            }
+        }
+        for config in &mut configs {
+            config.set_tasks(tag_filter, &profiles, &current_dir, &mut shell_grunt2);
+        }
+
+        // Configs are in the order they were given with '-f' and listed in 'configs', the first
+        // one that sets 'max_parallel' wins.
+        shell_grunt2.set_max_parallel(
+            jobs.or_else(|| configs.iter().find_map(|config| config.max_parallel)),
+        );
+        // Changing any of these reloads the config that loaded it.
+        let config_files = configs
+            .iter()
+            .flat_map(|config| config.reload_task.file_names.borrow().clone())
+            .collect::<Vec<_>>();
 
         // Ideally, the RecommendedWatcher would be owned by ShellGrunt2, but whenever I try that,
         // the tool crashes whenever it should receive an event on the channel. So it needs to stay
         // outside. :(
-        let (events_tx, events_rx) = mpsc::channel();
-        let mut watcher = notify::watcher(events_tx, Duration::from_millis(50)).unwrap();
-        watcher
-            .watch(&current_dir, notify::RecursiveMode::Recursive)
-            .unwrap();
-        watcher
-            .watch(&watcher_file, notify::RecursiveMode::Recursive)
-            .unwrap();
-
-        let should_reload = Arc::new(AtomicBool::new(false));
-        let mut tasks: Vec<Box<dyn Task>> = vec![Box::new(ReloadWatcherFile {
-            file_name: watcher_file.clone(),
-            should_reload: should_reload.clone(),
-        })];
-        for task in shell_grunt2::lua_task::run_file(&watcher_file) {
-            tasks.push(task);
+        let (file_events_tx, file_events_rx) = mpsc::channel();
+        let mut watcher = notify::watcher(file_events_tx, Duration::from_millis(50)).unwrap();
+        let mut roots = default_roots.clone();
+        for config in &configs {
+            roots.extend(config.watch_roots.iter().cloned());
         }
-        let mut shell_grunt2 = shell_grunt2::ShellGrunt2::new(&tasks, events_rx);
+        // Watching a path twice loses events, so directories inside of others are left out. Sorted,
+        // they come right after the directories containing them.
+        roots.sort();
+        let mut watched_roots: Vec<PathBuf> = Vec::new();
+        for root in roots {
+            if watched_roots
+                .iter()
+                .any(|watched| root.starts_with(watched))
+            {
+                continue;
+            }
+            if let Err(err) = watcher.watch(&root, notify::RecursiveMode::Recursive) {
+                println!("Cannot watch {}: {}", root.display(), err);
+            }
+            watched_roots.push(root);
+        }
+        // Watching the files themselves would miss editors that replace files on save, so their
+        // directories are watched instead.
+        let mut config_dirs = config_files
+            .iter()
+            .filter_map(|file| file.parent())
+            .filter(|dir| !watched_roots.iter().any(|root| dir.starts_with(root)))
+            .collect::<Vec<_>>();
+        config_dirs.sort();
+        config_dirs.dedup();
+        for dir in config_dirs {
+            if let Err(err) = watcher.watch(dir, notify::RecursiveMode::NonRecursive) {
+                println!("Cannot watch {}: {}", dir.display(), err);
+            }
+        }
+        // Stops once the watcher is dropped.
+        let forward_tx = events_tx.clone();
+        thread::spawn(move || {
+            for ev in file_events_rx {
+                if forward_tx.send(Event::FileSystem(ev)).is_err() {
+                    break;
+                }
+            }
+        });
 
         loop {
-            thread::sleep(std::time::Duration::from_millis(50));
             if saw_interrupt_signal.load(Ordering::SeqCst) {
+                println!(
+                    "Interrupted, stopping running commands. Press Ctrl-C again to kill them."
+                );
+                shell_grunt2.interrupt_all();
                 return;
             }
-            if should_reload.load(Ordering::SeqCst) {
+            if configs.iter().any(WatchedConfig::should_reload) {
                 break;
             }
             shell_grunt2.spin();
@@ -119,18 +564,88 @@ fn watch_file_events(watcher_file: impl AsRef<Path>) {
 fn main() {
     let matches = clap::App::new("shell_grunt2")
         .version(self_update::cargo_crate_version!())
-        .about("Watches the file system and executes commands from a Lua file.")
+        .about("Watches the file system and executes commands from a Lua or TOML file.")
         .arg(
             clap::Arg::with_name("file")
                 .short("f")
                 .takes_value(true)
-                .help("Lua file to use [watcher.lua]"),
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help(
+                    "Lua or TOML file to use, can be given more than once [watcher.lua, or \
+                     shell_grunt2.toml if it exists]",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .takes_value(true)
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err("must be a positive number".to_string()),
+                })
+                .help(
+                    "Maximum number of tasks running at the same time [max_parallel of the first \
+                     config file that sets it]",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("no-ignore")
+                .long("no-ignore")
+                .help("Also react to files ignored by .gitignore and .ignore files"),
+        )
+        .arg(
+            clap::Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .help("Only use the tasks with one of the tags of this profile from the config"),
+        )
+        .arg(
+            clap::Arg::with_name("tag")
+                .long("tag")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only use the tasks with this tag, can be given more than once"),
+        )
+        .arg(
+            clap::Arg::with_name("exclude-tag")
+                .long("exclude-tag")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Do not use the tasks with this tag, can be given more than once"),
+        )
+        .arg(
+            clap::Arg::with_name("watch")
+                .long("watch")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DIR")
+                .help("Also watch this directory for all tasks, can be given more than once"),
         )
         .arg(
             clap::Arg::with_name("update")
                 .long("update")
                 .help("Update binary in-place from latest release"),
         )
+        .subcommand(
+            clap::SubCommand::with_name("run")
+                .about("Runs the named tasks once and exits with their status.")
+                .arg(
+                    clap::Arg::with_name("task")
+                        .required(true)
+                        .multiple(true)
+                        .help("Names of the tasks to run, in order"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("check")
+                .about("Reports all problems in the config file without running anything."),
+        )
         .get_matches();
 
     if matches.is_present("update") {
@@ -138,20 +653,101 @@ fn main() {
         return;
     }
 
-    let watcher_file = matches.value_of("file").unwrap_or("watcher.lua");
+    let default_file =
+        if !Path::new("watcher.lua").exists() && Path::new("shell_grunt2.toml").exists() {
+            "shell_grunt2.toml"
+        } else {
+            "watcher.lua"
+        };
+    let files = |matches: &clap::ArgMatches| {
+        matches
+            .values_of("file")
+            .map(|files| files.map(PathBuf::from).collect::<Vec<_>>())
+    };
+    let mut watcher_files = files(&matches).unwrap_or_else(|| vec![PathBuf::from(default_file)]);
+    // The same file given twice would otherwise be locked twice.
+    let mut seen = Vec::new();
+    watcher_files.retain(|file| {
+        let file = file.canonicalize().unwrap_or_else(|_| file.clone());
+        let is_new = !seen.contains(&file);
+        seen.push(file);
+        is_new
+    });
 
-    let _lockfile = match lockfile::Lockfile::new(watcher_file) {
-        Ok(lockfile) => lockfile,
-        Err(lockfile::AlreadyExists(path)) => {
-            println!(
-                "Another shell grunt is already running for {}. \
-                 Delete\n\n    {}\n\nif you sure this is untrue. Exiting.",
-                watcher_file,
-                path.to_string_lossy()
-            );
-            process::exit(1);
+    if let Some(matches) = matches.subcommand_matches("run") {
+        let watcher_files = files(matches).unwrap_or(watcher_files);
+        let names = matches.values_of("task").unwrap().collect::<Vec<_>>();
+        process::exit(run_tasks(&watcher_files, &names));
+    }
+    if let Some(matches) = matches.subcommand_matches("check") {
+        let watcher_files = files(matches).unwrap_or(watcher_files);
+        process::exit(check(&watcher_files));
+    }
+
+    let mut watch_dirs = Vec::new();
+    for dir in matches.values_of("watch").into_iter().flatten() {
+        match std::fs::canonicalize(dir) {
+            Ok(dir) if dir.is_dir() => watch_dirs.push(dir),
+            _ => {
+                println!("Watch directory '{}' does not exist.", dir);
+                process::exit(1);
+            }
+        }
+    }
+
+    // The lockfiles and the watcher need the config files to exist.
+    let mut canonical_files = Vec::new();
+    for file in &watcher_files {
+        match std::fs::canonicalize(file) {
+            Ok(file) => canonical_files.push(file),
+            Err(err) => {
+                let message = format!("Cannot read file: {}", err);
+                println!("{}", config::ConfigError::new(file, message));
+                process::exit(1);
+            }
         }
+    }
+    let watcher_files = canonical_files;
+
+    let mut lockfiles = Vec::new();
+    for watcher_file in &watcher_files {
+        match lockfile::Lockfile::new(watcher_file) {
+            Ok(lockfile) => lockfiles.push(lockfile),
+            Err(lockfile::AlreadyExists(path)) => {
+                println!(
+                    "Another shell grunt is already running for {}. \
+                     Delete\n\n    {}\n\nif you sure this is untrue. Exiting.",
+                    watcher_file.display(),
+                    path.to_string_lossy()
+                );
+                // Exiting skips the destructors.
+                drop(lockfiles);
+                process::exit(1);
+            }
+        }
+    }
+
+    let jobs = matches.value_of("jobs").map(|v| v.parse().unwrap());
+    let values = |name| {
+        matches
+            .values_of(name)
+            .map(|values| values.map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+    let tag_filter = config::TagFilter {
+        profile: matches.value_of("profile").map(str::to_string),
+        tags: values("tag"),
+        exclude_tags: values("exclude-tag"),
     };
+    watch_file_events(
+        &watcher_files,
+        jobs,
+        matches.is_present("no-ignore"),
+        &tag_filter,
+        &watch_dirs,
+    );
 
-    watch_file_events(watcher_file);
+    // Like shells do for commands that were stopped by SIGINT.
+    drop(lockfiles);
+    process::exit(128 + libc::SIGINT);
 }
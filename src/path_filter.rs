@@ -0,0 +1,51 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Path, PathBuf};
+
+/// Compiles 'patterns' into one set, or None if there are no patterns.
+fn build_glob_set(patterns: &[String]) -> Result<Option<GlobSet>, String> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|err| format!("Invalid glob '{}': {}", pattern, err))?;
+        builder.add(glob);
+    }
+    builder.build().map(Some).map_err(|err| err.to_string())
+}
+
+/// The 'include' and 'exclude' globs of a task.
+pub struct PathFilter {
+    // Relative globs match against paths relative to this directory.
+    base_dir: PathBuf,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl PathFilter {
+    pub fn new(base_dir: &Path, include: &[String], exclude: &[String]) -> Result<Self, String> {
+        Ok(PathFilter {
+            base_dir: base_dir.to_path_buf(),
+            include: build_glob_set(include)?,
+            exclude: build_glob_set(exclude)?,
+        })
+    }
+
+    /// Returns true if 'path' matches any 'include' glob, or there are none, and no 'exclude'
+    /// glob.
+    pub fn matches(&self, path: &Path) -> bool {
+        let path = path.strip_prefix(&self.base_dir).unwrap_or(path);
+        let included = self
+            .include
+            .as_ref()
+            .is_none_or(|globs| globs.is_match(path));
+        let excluded = self
+            .exclude
+            .as_ref()
+            .is_some_and(|globs| globs.is_match(path));
+        included && !excluded
+    }
+}
@@ -1,52 +1,259 @@
+use crate::config::ConfigError;
+use crate::dispatch::Event;
 use floating_duration::TimeFormat;
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::OpenOptions;
 use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path;
 use std::process;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use term;
 use time;
 
 pub trait RunningTask {
     fn done(&mut self) -> bool;
-    fn wait(self: Box<Self>);
+    /// Blocks until the task is done. Returns its exit code, which is 0 if it succeeded.
+    fn wait(self: Box<Self>) -> i32;
     fn interrupt(self: Box<Self>);
 }
 
+/// Why and as which run a task is started.
+pub struct RunContext {
+    pub task_name: String,
+    pub run_id: u64,
+    // All files that changed since the last run was started, in order of their first change.
+    pub changed_files: Vec<path::PathBuf>,
+    // Used to wake up the dispatcher when a child process exits.
+    pub events_tx: mpsc::Sender<Event>,
+}
+
 pub trait Runnable {
-    fn run(&self) -> Box<dyn RunningTask>;
+    fn run(&self, context: &RunContext) -> Box<dyn RunningTask>;
+}
+
+/// What happened to a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Create,
+    Write,
+    Remove,
+    Rename,
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Create => "create",
+            EventKind::Write => "write",
+            EventKind::Remove => "remove",
+            EventKind::Rename => "rename",
+        }
+    }
+}
+
+impl std::str::FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "create" => Ok(EventKind::Create),
+            "write" => Ok(EventKind::Write),
+            "remove" => Ok(EventKind::Remove),
+            "rename" => Ok(EventKind::Rename),
+            _ => Err(format!(
+                "Unknown event '{}', expected 'create', 'write', 'remove' or 'rename'.",
+                s
+            )),
+        }
+    }
+}
+
+pub struct FileEvent {
+    pub path: path::PathBuf,
+    pub kind: EventKind,
+    // The old path of a renamed file.
+    pub from: Option<path::PathBuf>,
+}
+
+/// What to do when a task is triggered again while it is still running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnChange {
+    /// Interrupt the running task and start it again.
+    Restart,
+    /// Let the running task finish, then run it once more.
+    Queue,
+    /// Drop all changes that happen while the task is running.
+    Ignore,
+    /// Let the running task finish, then run it once more when no change arrived for the start
+    /// delay, counted from the end of the previous run.
+    WaitForIdle,
+}
+
+impl std::str::FromStr for OnChange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restart" => Ok(OnChange::Restart),
+            "queue" => Ok(OnChange::Queue),
+            "ignore" => Ok(OnChange::Ignore),
+            "wait_for_idle" => Ok(OnChange::WaitForIdle),
+            _ => Err(format!(
+                "Unknown on_change value '{}', expected 'restart', 'queue', 'ignore' or \
+                 'wait_for_idle'.",
+                s
+            )),
+        }
+    }
 }
 
 pub trait Task: Runnable {
-    fn should_run(&self, _: &path::Path) -> bool;
+    fn should_run(&self, _: &FileEvent) -> bool;
     fn start_delay(&self) -> time::Duration;
+
+    /// The name other tasks can use to refer to this task.
+    fn name(&self) -> Option<String> {
+        None
+    }
+
+    /// Names of tasks that must finish before this task is started, if both are triggered.
+    fn depends_on(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn on_change(&self) -> OnChange {
+        OnChange::Restart
+    }
+
+    /// If false, changes to files that are ignored by '.gitignore' files are seen as well.
+    fn respect_gitignore(&self) -> bool {
+        true
+    }
+
+    /// Tasks in the same exclusive group never run at the same time.
+    fn exclusive_group(&self) -> Option<String> {
+        None
+    }
+
+    /// Tags to select the task with '--tag', '--exclude-tag' and profiles.
+    fn tags(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Canonical directories to watch for this task. The task only sees changes below them, or
+    /// below the current directory and '--watch' directories if there are none.
+    fn watch_roots(&self) -> &[path::PathBuf] {
+        &[]
+    }
 }
 
+#[derive(Clone)]
 pub struct ShellCommand {
     pub name: String,
     pub command: String,
     pub work_directory: Option<path::PathBuf>,
 }
 
+/// Points in a run at which a task can run code of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hook {
+    /// Before the first command starts.
+    Start,
+    /// After a command succeeded.
+    Success,
+    /// After a command failed.
+    Failure,
+    /// After the last command of the chain, or after the run failed or got cancelled.
+    Finish,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::Start => "on_start",
+            Hook::Success => "on_success",
+            Hook::Failure => "on_failure",
+            Hook::Finish => "on_finish",
+        }
+    }
+}
+
+/// What hooks get to know about a run. The command related fields are empty for 'Hook::Start'.
+#[derive(Debug, Default)]
+pub struct RunResult {
+    pub task_name: String,
+    pub changed_files: Vec<path::PathBuf>,
+    pub command: Option<String>,
+    // None if the command was killed by a signal.
+    pub exit_code: Option<i32>,
+    // Of the command, or of the whole run for 'Hook::Finish'.
+    pub duration: std::time::Duration,
+    pub success: bool,
+    pub cancelled: bool,
+    // The last lines the command wrote to stderr.
+    pub stderr_tail: Vec<String>,
+}
+
+pub trait RunHooks {
+    /// Implementations report their own errors, a failing hook never stops a run.
+    fn call(&self, hook: Hook, result: &RunResult);
+}
+
 pub trait ShellTask: Task {
-    // Will run the first command, on success the second..
-    fn commands(&self) -> Vec<ShellCommand>;
+    // Will run the first command, on success the second.. 'changed_files' are the files that
+    // triggered the run.
+    fn commands(&self, changed_files: &[path::PathBuf]) -> Result<Vec<ShellCommand>, ConfigError>;
     fn environment(&self) -> Option<HashMap<String, String>>;
     fn redirect_stdout(&self) -> Option<path::PathBuf>;
     fn redirect_stderr(&self) -> Option<path::PathBuf>;
     fn supress_stdout(&self) -> bool;
     fn supress_stderr(&self) -> bool;
+    // Signal sent to the process group of a running command when it gets interrupted.
+    fn stop_signal(&self) -> libc::c_int;
+    // How long an interrupted command gets to exit before it is killed.
+    fn stop_timeout(&self) -> time::Duration;
+
+    fn hooks(&self) -> Option<Box<dyn RunHooks>> {
+        None
+    }
 }
 
-fn handle_output<R: BufRead, W: Write>(reader: R, echo: bool, mut redirect: Option<BufWriter<W>>) {
+/// Parses signal names like 'SIGTERM', 'TERM' or '15'.
+pub fn signal_from_name(name: &str) -> Option<libc::c_int> {
+    if let Ok(number) = name.parse() {
+        return Some(number);
+    }
+    let signal = match name.trim_start_matches("SIG") {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        _ => return None,
+    };
+    Some(signal)
+}
+
+// How many lines of stderr are kept around for hooks.
+const STDERR_TAIL_LINES: usize = 20;
+
+fn handle_output<R: BufRead, W: Write>(
+    reader: R,
+    echo: bool,
+    mut redirect: Option<BufWriter<W>>,
+    tail: Option<Arc<Mutex<VecDeque<String>>>>,
+) {
     for line in reader.lines() {
-        if !line.is_ok() {
-            continue;
-        }
-        let line = line.unwrap();
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => continue,
+        };
 
         // TODO(sirver): My understanding is that \x1b[ should start every ANSI sequence, but I
         // also see \x1b( in my outputs - so I filtered that too. Figure out a more principled way
@@ -61,37 +268,189 @@ fn handle_output<R: BufRead, W: Write>(reader: R, echo: bool, mut redirect: Opti
         }
         let no_color = REMOVE_ANSI.replace_all(&line, "");
         let no_shift = REMOVE_SHIFT_INOUT.replace_all(&no_color, "");
-        redirect
-            .as_mut()
-            .map(|w| writeln!(w, "{}", no_shift).unwrap());
+        if let Some(w) = redirect.as_mut() {
+            writeln!(w, "{}", no_shift).unwrap();
+        }
+        if let Some(tail) = tail.as_ref() {
+            let mut tail = tail.lock().unwrap();
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(no_shift.into_owned());
+        }
         if echo {
             println!("{}", line);
         }
     }
 }
 
+lazy_static! {
+    // Process groups of all commands that are currently running, so that they can be killed from
+    // a signal handler.
+    static ref RUNNING_PROCESS_GROUPS: Mutex<HashSet<libc::pid_t>> = Mutex::new(HashSet::new());
+}
+
+/// Kills the process groups of all running commands right away.
+pub fn kill_all_running() {
+    for pgid in RUNNING_PROCESS_GROUPS.lock().unwrap().iter() {
+        unsafe {
+            libc::killpg(*pgid, libc::SIGKILL);
+        }
+    }
+}
+
 struct RunningChildState {
     name: String,
     child: process::Child,
     start_time: time::PreciseTime,
     io_threads: Vec<thread::JoinHandle<()>>,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl RunningChildState {
+    /// Gives the io threads a moment to handle the last output of the exited child. Grandchildren
+    /// might keep the pipes open, so this does not wait for them to finish.
+    fn wait_for_output(&self) {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(100);
+        while !self.io_threads.iter().all(|t| t.is_finished())
+            && std::time::Instant::now() < deadline
+        {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    fn stderr_tail(&self) -> Vec<String> {
+        self.stderr_tail.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Sends 'signal' to the process group of the child and waits for the child to exit and its
+    /// output to be closed. If that takes longer than 'timeout', the whole group is killed. The
+    /// child is reaped and all output is handled when this returns.
+    fn stop(mut self, signal: libc::c_int, timeout: std::time::Duration) {
+        // The child is the leader of its own process group.
+        let pgid = self.child.id() as libc::pid_t;
+        unsafe {
+            libc::killpg(pgid, signal);
+        }
+
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let exited = self.child.try_wait().expect("try_wait").is_some();
+            // Grandchildren might still hold on to the output pipes.
+            if exited && self.io_threads.iter().all(|t| t.is_finished()) {
+                break;
+            }
+            if std::time::Instant::now() >= deadline {
+                unsafe {
+                    libc::killpg(pgid, libc::SIGKILL);
+                }
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        self.child.wait().expect("wait");
+        for handle in self.io_threads.drain(..) {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Drop for RunningChildState {
+    fn drop(&mut self) {
+        RUNNING_PROCESS_GROUPS
+            .lock()
+            .unwrap()
+            .remove(&(self.child.id() as libc::pid_t));
+    }
+}
+
+/// Splits 'command' into arguments. An argument that is exactly '${changed_files}' expands to
+/// one argument per changed file, elsewhere it is replaced by all changed files separated by
+/// spaces. '${changed_file}' is replaced by the file that changed last. The result is empty if
+/// there is nothing to run.
+fn expand_arguments(command: &str, changed_files: &[path::PathBuf]) -> Vec<String> {
+    let changed_file = changed_files
+        .last()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let all_changed_files = changed_files
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    let mut args = Vec::new();
+    for arg in command.split_whitespace() {
+        if arg == "${changed_files}" {
+            args.extend(all_changed_files.iter().cloned());
+            continue;
+        }
+        args.push(
+            arg.replace("${changed_files}", &all_changed_files.join(" "))
+                .replace("${changed_file}", &changed_file),
+        );
+    }
+    args
+}
+
+/// Sends 'Event::ChildExited' once the process 'pid' has exited. The child is not reaped, that is
+/// still done through 'Child::try_wait' or 'Child::wait'.
+fn notify_on_exit(pid: u32, events_tx: mpsc::Sender<Event>) {
+    thread::spawn(move || {
+        loop {
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            let rv = unsafe {
+                libc::waitid(
+                    libc::P_PID,
+                    pid as libc::id_t,
+                    &mut info,
+                    libc::WEXITED | libc::WNOWAIT,
+                )
+            };
+            if rv == 0 || std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted
+            {
+                break;
+            }
+        }
+        let _ = events_tx.send(Event::ChildExited);
+    });
 }
 
 struct RunningShellTask {
+    task_name: String,
     commands: Vec<ShellCommand>,
-    environment: Option<HashMap<String, String>>,
+    changed_files: Vec<path::PathBuf>,
+    events_tx: mpsc::Sender<Event>,
+    environment: HashMap<String, String>,
     echo_stdout: bool,
     redirect_stdout: Option<path::PathBuf>,
     echo_stderr: bool,
     redirect_stderr: Option<path::PathBuf>,
     running_child: Option<RunningChildState>,
+    stop_signal: libc::c_int,
+    stop_timeout: std::time::Duration,
     progress_reporter: Box<dyn ProgressReporter>,
+    hooks: Option<Box<dyn RunHooks>>,
+    start_time: time::PreciseTime,
+    // 0 until a command failed, then its exit code, or 128 + signal if it was killed by one. 1 if
+    // the commands could not be determined.
+    exit_code: i32,
 }
 
-trait ProgressReporter {
+pub trait ProgressReporter {
     fn clear_screen(&self);
     fn starting_command(&self, name: &str);
     fn command_finished(&self, name: &str, duration: std::time::Duration, success: bool);
+    fn command_cancelled(&self, name: &str, duration: std::time::Duration);
+    fn waiting_for_slot(&self, name: &str);
+}
+
+/// Returns a progress reporter fitting for the current stdout.
+pub fn progress_reporter() -> Box<dyn ProgressReporter> {
+    match term::stdout() {
+        None => Box::new(DumbProgressReporter {}),
+        Some(_) => Box::new(TermProgressReporter {}),
+    }
 }
 
 /// A pretty printing progress reporter.
@@ -125,7 +484,28 @@ impl ProgressReporter for TermProgressReporter {
         }
         terminal.reset().unwrap();
         write!(terminal, "({})", TimeFormat(duration)).unwrap();
-        writeln!(terminal, "").unwrap();
+        writeln!(terminal).unwrap();
+    }
+
+    fn command_cancelled(&self, name: &str, duration: std::time::Duration) {
+        let mut terminal = term::stdout().unwrap();
+        terminal.fg(term::color::CYAN).unwrap();
+        write!(terminal, "==> {}: ", name).unwrap();
+        terminal.fg(term::color::YELLOW).unwrap();
+        write!(terminal, "Cancelled. ").unwrap();
+        terminal.reset().unwrap();
+        write!(terminal, "({})", TimeFormat(duration)).unwrap();
+        writeln!(terminal).unwrap();
+    }
+
+    fn waiting_for_slot(&self, name: &str) {
+        let mut terminal = term::stdout().unwrap();
+        terminal.fg(term::color::CYAN).unwrap();
+        write!(terminal, "==> {}: ", name).unwrap();
+        terminal.fg(term::color::YELLOW).unwrap();
+        writeln!(terminal, "Waiting for slot.").unwrap();
+        terminal.reset().unwrap();
+        terminal.flush().unwrap();
     }
 }
 
@@ -147,41 +527,88 @@ impl ProgressReporter for DumbProgressReporter {
             print!("Failed. ");
         }
         print!("({})", TimeFormat(duration));
-        println!("");
+        println!();
+    }
+
+    fn command_cancelled(&self, name: &str, duration: std::time::Duration) {
+        println!("==> {}: Cancelled. ({})", name, TimeFormat(duration));
+    }
+
+    fn waiting_for_slot(&self, name: &str) {
+        println!("==> {}: Waiting for slot.", name);
     }
 }
 
 impl RunningShellTask {
-    pub fn spawn(
-        commands: Vec<ShellCommand>,
-        environment: Option<HashMap<String, String>>,
-        echo_stdout: bool,
-        redirect_stdout: Option<path::PathBuf>,
-        echo_stderr: bool,
-        redirect_stderr: Option<path::PathBuf>,
-    ) -> Self {
-        let progress_reporter: Box<dyn ProgressReporter> = match term::stdout() {
-            None => Box::new(DumbProgressReporter {}),
-            Some(_) => Box::new(TermProgressReporter {}),
+    pub fn spawn(task: &dyn ShellTask, context: &RunContext) -> Self {
+        let mut environment = task.environment().unwrap_or_default();
+        environment.insert("SHELL_GRUNT_TASK".to_string(), context.task_name.clone());
+        environment.insert("SHELL_GRUNT_RUN_ID".to_string(), context.run_id.to_string());
+        let changed_files = context
+            .changed_files
+            .iter()
+            .map(|p| p.to_string_lossy())
+            .collect::<Vec<_>>();
+        environment.insert(
+            "SHELL_GRUNT_CHANGED_FILES".to_string(),
+            changed_files.join("\n"),
+        );
+
+        let progress_reporter = progress_reporter();
+        progress_reporter.clear_screen();
+        // A broken task gets reported and is done right away.
+        let (mut commands, exit_code) = match task.commands(&context.changed_files) {
+            Ok(commands) => (commands, 0),
+            Err(err) => {
+                println!("{}", err);
+                (Vec::new(), 1)
+            }
         };
+        // E.g. a command that is only '${changed_files}' has nothing to run without changed files.
+        commands.retain(|command| {
+            !expand_arguments(&command.command, &context.changed_files).is_empty()
+        });
 
         let mut this = RunningShellTask {
+            task_name: context.task_name.clone(),
             commands,
+            changed_files: context.changed_files.clone(),
+            events_tx: context.events_tx.clone(),
             environment,
-            echo_stdout,
-            redirect_stdout,
-            echo_stderr,
-            redirect_stderr,
+            echo_stdout: !task.supress_stdout(),
+            redirect_stdout: task.redirect_stdout(),
+            echo_stderr: !task.supress_stderr(),
+            redirect_stderr: task.redirect_stderr(),
             running_child: None,
+            stop_signal: task.stop_signal(),
+            stop_timeout: task.stop_timeout().to_std().unwrap_or_default(),
             progress_reporter,
+            hooks: task.hooks(),
+            start_time: time::PreciseTime::now(),
+            exit_code,
         };
-
-        this.progress_reporter.clear_screen();
-
+        if !this.commands.is_empty() {
+            this.call_hook(Hook::Start, this.run_result());
+        }
         this.run_next_command(true);
         this
     }
 
+    /// The parts of a result that are the same throughout the run.
+    fn run_result(&self) -> RunResult {
+        RunResult {
+            task_name: self.task_name.clone(),
+            changed_files: self.changed_files.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn call_hook(&self, hook: Hook, result: RunResult) {
+        if let Some(hooks) = self.hooks.as_ref() {
+            hooks.call(hook, &result);
+        }
+    }
+
     fn run_next_command(&mut self, is_first: bool) {
         assert!(self.running_child.is_none());
         if self.commands.is_empty() {
@@ -190,29 +617,46 @@ impl RunningShellTask {
         let command = self.commands.remove(0);
 
         // TODO(sirver): This should use something like 'conch-parser', this is quite cheap.
-        let args = command.command.split_whitespace().collect::<Vec<&str>>();
+        let args = expand_arguments(&command.command, &self.changed_files);
         self.progress_reporter.starting_command(&command.name);
 
         let start_time = time::PreciseTime::now();
         let mut child = {
-            let mut child = process::Command::new(args[0]);
+            let mut child = process::Command::new(&args[0]);
             child
                 .args(&args[1..])
-                .stdin(process::Stdio::inherit())
+                // Our own process group, so that interrupting also reaches grandchildren. Being
+                // in a background process group, reading from the terminal would stop the child.
+                .process_group(0)
+                .stdin(process::Stdio::null())
                 .stdout(process::Stdio::piped())
                 .stderr(process::Stdio::piped());
             if let Some(path) = command.work_directory {
                 child.current_dir(path);
             }
-            if let Some(ref environment) = self.environment {
-                for (k, v) in environment {
-                    child.env(k, v);
+            for (k, v) in &self.environment {
+                child.env(k, v);
+            }
+            match child.spawn() {
+                Ok(child) => child,
+                Err(err) => {
+                    println!("Failed to execute '{}': {}", args[0], err);
+                    // Like the shell does for programs it cannot find or run.
+                    let exit_code = if err.kind() == std::io::ErrorKind::NotFound {
+                        127
+                    } else {
+                        126
+                    };
+                    self.command_failed_to_start(command.name, start_time, exit_code);
+                    return;
                 }
             }
-            child
-                .spawn()
-                .unwrap_or_else(|e| panic!("failed to execute: {}", e))
         };
+        RUNNING_PROCESS_GROUPS
+            .lock()
+            .unwrap()
+            .insert(child.id() as libc::pid_t);
+        notify_on_exit(child.id(), self.events_tx.clone());
 
         let mut io_threads = Vec::new();
         let creation_func = |p| {
@@ -231,7 +675,7 @@ impl RunningShellTask {
             .as_ref()
             .map(|path| BufWriter::with_capacity(512, creation_func(path).unwrap()));
         io_threads.push(thread::spawn(move || {
-            handle_output(stdout, echo_stdout, redirect_stdout);
+            handle_output(stdout, echo_stdout, redirect_stdout, None);
         }));
         let stderr = BufReader::new(child.stderr.take().unwrap());
         let echo_stderr = self.echo_stderr;
@@ -239,40 +683,114 @@ impl RunningShellTask {
             .redirect_stderr
             .as_ref()
             .map(|path| BufWriter::with_capacity(512, creation_func(path).unwrap()));
+        let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+        let tail = stderr_tail.clone();
         io_threads.push(thread::spawn(move || {
-            handle_output(stderr, echo_stderr, redirect_stderr);
+            handle_output(stderr, echo_stderr, redirect_stderr, Some(tail));
         }));
         self.running_child = Some(RunningChildState {
             name: command.name,
             io_threads,
             child,
             start_time,
+            stderr_tail,
         });
     }
 
-    fn current_command_finished(&mut self, success: bool) {
+    /// Ends the run like a failed command would, e.g. when the program does not exist.
+    fn command_failed_to_start(
+        &mut self,
+        name: String,
+        start_time: time::PreciseTime,
+        exit_code: i32,
+    ) {
+        let duration = start_time.to(time::PreciseTime::now()).to_std().unwrap();
+        self.exit_code = exit_code;
+        self.progress_reporter
+            .command_finished(&name, duration, false);
+        self.call_hook(
+            Hook::Failure,
+            RunResult {
+                command: Some(name.clone()),
+                duration,
+                ..self.run_result()
+            },
+        );
+        self.call_hook(
+            Hook::Finish,
+            RunResult {
+                command: Some(name),
+                duration: self
+                    .start_time
+                    .to(time::PreciseTime::now())
+                    .to_std()
+                    .unwrap(),
+                ..self.run_result()
+            },
+        );
+    }
+
+    fn current_command_finished(&mut self, status: process::ExitStatus) {
         assert!(self.running_child.is_some());
         let running_child = self.running_child.take().unwrap();
+        running_child.wait_for_output();
 
         let duration = running_child
             .start_time
             .to(time::PreciseTime::now())
             .to_std()
             .unwrap();
+        let success = status.success();
+        if !success {
+            // Like the shell, report commands killed by a signal as 128 + signal.
+            self.exit_code = status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or(0));
+        }
         self.progress_reporter
             .command_finished(&running_child.name, duration, success);
+
+        let result = RunResult {
+            command: Some(running_child.name.clone()),
+            exit_code: status.code(),
+            duration,
+            success,
+            stderr_tail: running_child.stderr_tail(),
+            ..self.run_result()
+        };
         if success {
+            self.call_hook(Hook::Success, result);
             self.run_next_command(false);
+            if self.running_child.is_some() {
+                return;
+            }
+        } else {
+            self.call_hook(Hook::Failure, result);
         }
+        self.call_hook(
+            Hook::Finish,
+            RunResult {
+                command: Some(running_child.name.clone()),
+                exit_code: status.code(),
+                duration: self
+                    .start_time
+                    .to(time::PreciseTime::now())
+                    .to_std()
+                    .unwrap(),
+                success,
+                stderr_tail: running_child.stderr_tail(),
+                ..self.run_result()
+            },
+        );
     }
 }
 
 impl Drop for RunningShellTask {
     fn drop(&mut self) {
-        if let Some(mut running_child) = self.running_child.take() {
-            for handle in running_child.io_threads.drain(..) {
-                handle.join().unwrap();
-            }
+        // E.g. when the config is reloaded. Without stopping it first, waiting for a long running
+        // command would block until it is done.
+        if let Some(running_child) = self.running_child.take() {
+            running_child.stop(self.stop_signal, self.stop_timeout);
         }
     }
 }
@@ -283,7 +801,7 @@ impl RunningTask for RunningShellTask {
             return true;
         }
 
-        let success = match self
+        let status = match self
             .running_child
             .as_mut()
             .unwrap()
@@ -291,43 +809,55 @@ impl RunningTask for RunningShellTask {
             .try_wait()
             .expect("try_wait")
         {
-            Some(status) => status.success(),
+            Some(status) => status,
             None => return false,
         };
-        self.current_command_finished(success);
+        self.current_command_finished(status);
         self.done()
     }
 
-    fn wait(mut self: Box<Self>) {
-        if self.done() {
-            return;
+    fn wait(mut self: Box<Self>) -> i32 {
+        while let Some(running_child) = self.running_child.as_mut() {
+            let status = running_child.child.wait().expect("wait");
+            self.current_command_finished(status);
         }
-        self.running_child
-            .take()
-            .map(|mut r| r.child.wait().expect("wait"));
-        self.wait()
+        self.exit_code
     }
 
     fn interrupt(mut self: Box<Self>) {
         if self.done() {
             return;
         }
-        self.running_child
-            .take()
-            .map(|mut r| r.child.kill().expect("kill"));
+        if let Some(running_child) = self.running_child.take() {
+            let name = running_child.name.clone();
+            let start_time = running_child.start_time;
+            let stderr_tail = running_child.stderr_tail.clone();
+            running_child.stop(self.stop_signal, self.stop_timeout);
+            let duration = start_time.to(time::PreciseTime::now()).to_std().unwrap();
+            self.progress_reporter.command_cancelled(&name, duration);
+
+            let stderr_tail = stderr_tail.lock().unwrap().iter().cloned().collect();
+            self.call_hook(
+                Hook::Finish,
+                RunResult {
+                    command: Some(name),
+                    duration: self
+                        .start_time
+                        .to(time::PreciseTime::now())
+                        .to_std()
+                        .unwrap(),
+                    cancelled: true,
+                    stderr_tail,
+                    ..self.run_result()
+                },
+            );
+        }
     }
 }
 
 impl<T: ShellTask> Runnable for T {
     /// Dispatches to 'program' with 'str'.
-    fn run(&self) -> Box<dyn RunningTask> {
-        Box::new(RunningShellTask::spawn(
-            self.commands(),
-            self.environment(),
-            !self.supress_stdout(),
-            self.redirect_stdout(),
-            !self.supress_stderr(),
-            self.redirect_stderr(),
-        ))
+    fn run(&self, context: &RunContext) -> Box<dyn RunningTask> {
+        Box::new(RunningShellTask::spawn(self, context))
     }
 }
@@ -0,0 +1,337 @@
+use time;
+
+use crate::config::{self, CheckedFile, Config, ConfigError};
+use crate::dispatch;
+use crate::path_filter::PathFilter;
+use crate::task::{self, EventKind, FileEvent, OnChange, ShellCommand, ShellTask, Task};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommandTable {
+    name: Option<String>,
+    command: String,
+    work_directory: Option<PathBuf>,
+}
+
+/// A command is either the command line itself or a table like a 'ShellCommand'.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CommandConfig {
+    Line(String),
+    Table(CommandTable),
+}
+
+impl CommandConfig {
+    fn into_shell_command(self) -> ShellCommand {
+        match self {
+            CommandConfig::Line(command) => ShellCommand {
+                name: command.clone(),
+                command,
+                work_directory: None,
+            },
+            CommandConfig::Table(CommandTable {
+                name,
+                command,
+                work_directory,
+            }) => ShellCommand {
+                name: name.unwrap_or_else(|| command.clone()),
+                command,
+                work_directory,
+            },
+        }
+    }
+}
+
+/// One '[[task]]' table, the keys are the same as for tasks in Lua.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TaskConfig {
+    name: Option<String>,
+    command: Option<CommandConfig>,
+    commands: Option<Vec<CommandConfig>>,
+    #[serde(default)]
+    environment: HashMap<String, String>,
+    redirect_stdout: Option<PathBuf>,
+    redirect_stderr: Option<PathBuf>,
+    #[serde(default)]
+    suppress_stdout: bool,
+    #[serde(default)]
+    suppress_stderr: bool,
+    start_delay: Option<i64>,
+    stop_signal: Option<String>,
+    stop_timeout: Option<i64>,
+    #[serde(default)]
+    depends_on: Vec<String>,
+    on_change: Option<String>,
+    respect_gitignore: Option<bool>,
+    exclusive_group: Option<String>,
+    #[serde(default)]
+    events: Vec<String>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    watch: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    max_parallel: Option<usize>,
+    #[serde(default)]
+    profiles: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    configs: Vec<String>,
+    #[serde(default, rename = "task")]
+    tasks: Vec<TaskConfig>,
+}
+
+struct TomlTask {
+    name: Option<String>,
+    commands: Vec<ShellCommand>,
+    environment: HashMap<String, String>,
+    redirect_stdout: Option<PathBuf>,
+    redirect_stderr: Option<PathBuf>,
+    suppress_stdout: bool,
+    suppress_stderr: bool,
+    start_delay: time::Duration,
+    stop_signal: libc::c_int,
+    stop_timeout: time::Duration,
+    depends_on: Vec<String>,
+    on_change: OnChange,
+    respect_gitignore: bool,
+    exclusive_group: Option<String>,
+    events: Vec<EventKind>,
+    path_filter: PathFilter,
+    tags: Vec<String>,
+    watch_roots: Vec<PathBuf>,
+}
+
+impl TomlTask {
+    fn new(config: TaskConfig, config_dir: &Path) -> Result<TomlTask, String> {
+        let commands = match (config.command, config.commands) {
+            (Some(command), None) => vec![command.into_shell_command()],
+            (None, Some(commands)) => commands
+                .into_iter()
+                .map(CommandConfig::into_shell_command)
+                .collect(),
+            (Some(_), Some(_)) => return Err("Expected either 'command' or 'commands'.".into()),
+            (None, None) => return Err("Expected 'command' or 'commands'.".into()),
+        };
+        let commands = commands
+            .into_iter()
+            .map(|command| ShellCommand {
+                work_directory: command.work_directory.map(|dir| config_dir.join(dir)),
+                ..command
+            })
+            .collect();
+        let stop_signal = match config.stop_signal {
+            Some(name) => task::signal_from_name(&name)
+                .ok_or_else(|| format!("Unknown stop_signal '{}'.", name))?,
+            None => libc::SIGTERM,
+        };
+        let on_change = match config.on_change {
+            Some(on_change) => on_change.parse()?,
+            None => OnChange::Restart,
+        };
+        let events = config
+            .events
+            .iter()
+            .map(|name| name.parse())
+            .collect::<Result<Vec<EventKind>, _>>()?;
+        Ok(TomlTask {
+            name: config.name,
+            commands,
+            environment: config.environment,
+            redirect_stdout: config.redirect_stdout.map(|file| config_dir.join(file)),
+            redirect_stderr: config.redirect_stderr.map(|file| config_dir.join(file)),
+            suppress_stdout: config.suppress_stdout,
+            suppress_stderr: config.suppress_stderr,
+            start_delay: time::Duration::milliseconds(config.start_delay.unwrap_or(50)),
+            stop_signal,
+            stop_timeout: time::Duration::milliseconds(config.stop_timeout.unwrap_or(2000)),
+            depends_on: config.depends_on,
+            on_change,
+            respect_gitignore: config.respect_gitignore.unwrap_or(true),
+            exclusive_group: config.exclusive_group,
+            events,
+            path_filter: PathFilter::new(config_dir, &config.include, &config.exclude)?,
+            tags: config.tags,
+            watch_roots: config::watch_roots(config_dir, &config.watch)?,
+        })
+    }
+}
+
+impl Task for TomlTask {
+    fn should_run(&self, event: &FileEvent) -> bool {
+        if !self.events.is_empty() && !self.events.contains(&event.kind) {
+            return false;
+        }
+        self.path_filter.matches(&event.path)
+    }
+
+    fn start_delay(&self) -> time::Duration {
+        self.start_delay
+    }
+
+    fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    fn depends_on(&self) -> Vec<String> {
+        self.depends_on.clone()
+    }
+
+    fn on_change(&self) -> OnChange {
+        self.on_change
+    }
+
+    fn respect_gitignore(&self) -> bool {
+        self.respect_gitignore
+    }
+
+    fn exclusive_group(&self) -> Option<String> {
+        self.exclusive_group.clone()
+    }
+
+    fn tags(&self) -> Vec<String> {
+        self.tags.clone()
+    }
+
+    fn watch_roots(&self) -> &[PathBuf] {
+        &self.watch_roots
+    }
+}
+
+impl ShellTask for TomlTask {
+    fn commands(&self, _: &[PathBuf]) -> Result<Vec<ShellCommand>, ConfigError> {
+        Ok(self.commands.clone())
+    }
+
+    fn redirect_stdout(&self) -> Option<PathBuf> {
+        self.redirect_stdout.clone()
+    }
+
+    fn redirect_stderr(&self) -> Option<PathBuf> {
+        self.redirect_stderr.clone()
+    }
+
+    fn supress_stdout(&self) -> bool {
+        self.suppress_stdout
+    }
+
+    fn supress_stderr(&self) -> bool {
+        self.suppress_stderr
+    }
+
+    fn stop_signal(&self) -> libc::c_int {
+        self.stop_signal
+    }
+
+    fn stop_timeout(&self) -> time::Duration {
+        self.stop_timeout
+    }
+
+    fn environment(&self) -> Option<HashMap<String, String>> {
+        Some(self.environment.clone())
+    }
+}
+
+/// Describes the task at 'index' for error messages, in the same way as for Lua tasks.
+fn describe(index: usize, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("task #{} ({})", index + 1, name),
+        None => format!("task #{}", index + 1),
+    }
+}
+
+fn parse_file(path: &Path) -> Result<FileConfig, ConfigError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| ConfigError::new(path, format!("Cannot read file: {}", err)))?;
+    toml::from_str(&contents).map_err(|err| {
+        let mut error = ConfigError::new(path, err.message());
+        error.line = err
+            .span()
+            .map(|span| contents[..span.start].matches('\n').count() as u32 + 1);
+        error
+    })
+}
+
+pub fn run_file(path: &Path) -> Result<Config, ConfigError> {
+    let file_config = parse_file(path)?;
+    let file = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let config_dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    let mut tasks = Vec::new();
+    for (index, task_config) in file_config.tasks.into_iter().enumerate() {
+        let description = describe(index, task_config.name.as_deref());
+        let task = TomlTask::new(task_config, &config_dir)
+            .map_err(|message| ConfigError::new(path, message).with_task(description))?;
+        tasks.push(Box::new(task) as Box<dyn Task>);
+    }
+    dispatch::dependencies(&tasks).map_err(|message| ConfigError::new(path, message))?;
+    Ok(Config {
+        tasks,
+        max_parallel: file_config
+            .max_parallel
+            .filter(|max_parallel| *max_parallel > 0),
+        profiles: file_config.profiles,
+        configs: config::included_configs(&config_dir, &file_config.configs),
+        files: vec![file],
+    })
+}
+
+/// Like 'lua_task::check_file'. Unknown keys and wrong types are already errors when loading, so
+/// this mostly adds the checks of the commands.
+pub fn check_file(path: &Path) -> CheckedFile {
+    let file_config = match parse_file(path) {
+        Ok(file_config) => file_config,
+        Err(err) => {
+            return CheckedFile {
+                problems: vec![err],
+                configs: Vec::new(),
+            }
+        }
+    };
+    let config_dir = path
+        .canonicalize()
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+
+    let mut problems = Vec::new();
+    let mut tasks = Vec::new();
+    let mut all_loaded = true;
+    for (index, task_config) in file_config.tasks.into_iter().enumerate() {
+        let description = describe(index, task_config.name.as_deref());
+        let task = match TomlTask::new(task_config, &config_dir) {
+            Ok(task) => task,
+            Err(message) => {
+                problems.push(ConfigError::new(path, message).with_task(description));
+                all_loaded = false;
+                continue;
+            }
+        };
+        for message in config::check_commands(&task.commands) {
+            problems.push(ConfigError::new(path, message).with_task(description.clone()));
+        }
+        tasks.push(Box::new(task) as Box<dyn Task>);
+    }
+    // Tasks that did not load would show up as unknown dependencies.
+    if all_loaded {
+        if let Err(message) = dispatch::dependencies(&tasks) {
+            problems.push(ConfigError::new(path, message));
+        }
+    }
+    CheckedFile {
+        problems,
+        configs: config::included_configs(&config_dir, &file_config.configs),
+    }
+}